@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tool::sre_breaker::atomic_rolling::AtomicRollingPolicy;
+use tool::sre_breaker::rolling::RollingPolicy;
+
+/// 每个线程的操作次数, 每 10 次写入做 1 次统计, 接近熔断器的调用比例
+const OPS_PER_THREAD: u64 = 10_000;
+
+fn run<A, S>(threads: usize, add: A, summary: S)
+where
+    A: Fn(u64) + Sync,
+    S: Fn() -> (u64, u64) + Sync,
+{
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for i in 0..OPS_PER_THREAD {
+                    if i % 10 == 0 {
+                        criterion::black_box(summary());
+                    }
+                    add(i & 1);
+                }
+            });
+        }
+    });
+}
+
+fn bench_rolling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rolling");
+    let duration = Duration::from_millis(100);
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements(threads as u64 * OPS_PER_THREAD));
+
+        let locked = RollingPolicy::new(10, duration).unwrap();
+        group.bench_with_input(BenchmarkId::new("rwlock", threads), &threads, |b, &n| {
+            b.iter(|| run(n, |v| locked.add(v), || locked.summary()))
+        });
+
+        let atomic = AtomicRollingPolicy::new(10, duration).unwrap();
+        group.bench_with_input(BenchmarkId::new("atomic", threads), &threads, |b, &n| {
+            b.iter(|| run(n, |v| atomic.add(v), || atomic.summary()))
+        });
+
+        let sharded = AtomicRollingPolicy::with_shards(10, duration, 0).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("atomic_sharded", threads),
+            &threads,
+            |b, &n| b.iter(|| run(n, |v| sharded.add(v), || sharded.summary())),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rolling);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tool::zip::{InnerZipFileInfo, ZipFileInfo};
+
+/// 2000 个 4 ~ 20KB 的 XML 报文
+fn declarations() -> ZipFileInfo {
+    ZipFileInfo::new(
+        (0..2000)
+            .map(|i| {
+                let xml = format!(
+                    "<declaration><id>{i}</id><goods>item-{}</goods><amount>{}</amount></declaration>",
+                    i * 7919 % 1000,
+                    i * 104729 % 100000
+                )
+                .repeat(50 + i % 200);
+                InnerZipFileInfo::new(format!("{i}.xml"), Some(xml.into_bytes()))
+            })
+            .collect(),
+    )
+}
+
+fn bench_zip(c: &mut Criterion) {
+    let size = declarations()
+        .inner
+        .iter()
+        .map(|item| item.file_content.as_ref().unwrap().len() as u64)
+        .sum();
+    let mut group = c.benchmark_group("zip_declarations");
+    group.throughput(Throughput::Bytes(size)).sample_size(10);
+    group.bench_function("zip_file_bytes", |b| {
+        b.iter_batched(
+            declarations,
+            |info| info.zip_file_bytes().unwrap(),
+            criterion::BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("zip_to_writer", |b| {
+        b.iter_batched(
+            declarations,
+            |info| info.zip_to_writer(Vec::new()).unwrap(),
+            criterion::BatchSize::PerIteration,
+        )
+    });
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("parallel", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched(
+                    declarations,
+                    |info| info.zip_file_bytes_parallel(threads).unwrap(),
+                    criterion::BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_zip);
+criterion_main!(benches);
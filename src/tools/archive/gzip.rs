@@ -0,0 +1,95 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+pub use flate2::Compression;
+
+use super::limits::copy_limited;
+use super::ArchiveLimits;
+
+/// gzip 压缩, 流式处理, 返回底层的 writer
+pub fn gzip_compress_stream<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    level: Compression,
+) -> Result<W> {
+    let mut encoder = GzEncoder::new(writer, level);
+    std::io::copy(&mut reader, &mut encoder)?;
+    Ok(encoder.finish()?)
+}
+
+/// gzip 解压, 流式处理, 返回底层的 writer. 多个 member 拼接的数据会全部解压. 不限制解压后的大小, 处理不可信的数据时用
+/// `gzip_decompress_stream_with_limits`
+pub fn gzip_decompress_stream<R: Read, W: Write>(reader: R, mut writer: W) -> Result<W> {
+    let mut decoder = MultiGzDecoder::new(reader);
+    std::io::copy(&mut decoder, &mut writer)?;
+    Ok(writer)
+}
+
+/// gzip 解压, 解压出的数据超过`limits.max_total_size`时返回`ArchiveLimitError`
+pub fn gzip_decompress_stream_with_limits<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    limits: &ArchiveLimits,
+) -> Result<W> {
+    let decoder = MultiGzDecoder::new(reader);
+    copy_limited(decoder, &mut writer, limits)?;
+    Ok(writer)
+}
+
+pub fn gzip_compress(data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+    gzip_compress_stream(data.as_ref(), Vec::new(), Compression::default())
+}
+
+/// 解压到内存, 受`ArchiveLimits::default()`限制
+pub fn gzip_decompress(data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+    gzip_decompress_stream_with_limits(data.as_ref(), Vec::new(), &ArchiveLimits::default())
+}
+
+/// 压缩单个文件, 如`app.log` -> `app.log.gz`
+pub fn gzip_compress_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let reader = std::fs::File::open(src)?;
+    let writer = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    gzip_compress_stream(reader, writer, Compression::default())?.flush()?;
+    Ok(())
+}
+
+pub fn gzip_decompress_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let reader = std::io::BufReader::new(std::fs::File::open(src)?);
+    let writer = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    gzip_decompress_stream(reader, writer)?.flush()?;
+    Ok(())
+}
+
+#[test]
+fn gzip_round_trip_test() {
+    let data = "gzip ".repeat(1000);
+    let compressed = gzip_compress(&data).unwrap();
+    assert!(compressed.len() < data.len());
+    assert_eq!(gzip_decompress(compressed).unwrap(), data.as_bytes());
+}
+
+#[test]
+fn gzip_multi_member_test() {
+    let mut compressed = gzip_compress("hello ").unwrap();
+    compressed.extend(gzip_compress("world").unwrap());
+    assert_eq!(gzip_decompress(&compressed).unwrap(), b"hello world");
+    let decompressed = gzip_decompress_stream(compressed.as_slice(), Vec::new()).unwrap();
+    assert_eq!(decompressed, b"hello world");
+}
+
+#[test]
+fn gzip_limits_test() {
+    let compressed = gzip_compress(vec![0u8; 1024 * 1024]).unwrap();
+    let limits = ArchiveLimits {
+        max_total_size: 1024,
+        ..Default::default()
+    };
+    let err = gzip_decompress_stream_with_limits(compressed.as_slice(), Vec::new(), &limits)
+        .err()
+        .unwrap();
+    let err = err.downcast::<super::ArchiveLimitError>().unwrap();
+    assert_eq!(err.limit_name(), "max_total_size");
+}
@@ -0,0 +1,108 @@
+/// 解压 tar / gzip / zstd 时的资源限制, 用来防止解压炸弹, 和`ZipLimits`的默认值相同
+///
+/// gzip 和 zstd 只有一个文件, 只检查`max_total_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveLimits {
+    /// 最多的条目数量, 包括目录
+    pub max_entries: usize,
+    /// 所有条目解压后的总大小
+    pub max_total_size: u64,
+    /// 单个条目解压后的大小
+    pub max_entry_size: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_total_size: 4 * 1024 * 1024 * 1024,
+            max_entry_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl ArchiveLimits {
+    /// 不做任何限制, 只用于可信的压缩包
+    pub fn unlimited() -> Self {
+        Self {
+            max_entries: usize::MAX,
+            max_total_size: u64::MAX,
+            max_entry_size: u64::MAX,
+        }
+    }
+
+    #[cfg(feature = "tar")]
+    pub(super) fn check_entries(&self, entries: usize) -> Result<(), ArchiveLimitError> {
+        if entries > self.max_entries {
+            return Err(ArchiveLimitError::TooManyEntries {
+                limit: self.max_entries,
+                actual: entries,
+            });
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_total_size(&self, total_size: u64) -> Result<(), ArchiveLimitError> {
+        if total_size > self.max_total_size {
+            return Err(ArchiveLimitError::TotalSizeExceeded {
+                limit: self.max_total_size,
+                actual: total_size,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tar")]
+    pub(super) fn check_entry(&self, name: &str, size: u64) -> Result<(), ArchiveLimitError> {
+        if size > self.max_entry_size {
+            return Err(ArchiveLimitError::EntrySizeExceeded {
+                name: name.to_string(),
+                limit: self.max_entry_size,
+                actual: size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 超过`ArchiveLimits`中某个限制时的错误, 可以从`anyhow::Error`中`downcast_ref`出来
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ArchiveLimitError {
+    #[error("archive: too many entries, limit = {limit}, actual = {actual}")]
+    TooManyEntries { limit: usize, actual: usize },
+    /// 流式解压时`actual`是超出时已经解压出的大小, 不是完整的大小
+    #[error("archive: total uncompressed size exceeded, limit = {limit}, actual = {actual}")]
+    TotalSizeExceeded { limit: u64, actual: u64 },
+    #[error(
+        "archive: entry `{name}` uncompressed size exceeded, limit = {limit}, actual = {actual}"
+    )]
+    EntrySizeExceeded {
+        name: String,
+        limit: u64,
+        actual: u64,
+    },
+}
+
+impl ArchiveLimitError {
+    /// 超出的是`ArchiveLimits`中的哪个字段
+    pub fn limit_name(&self) -> &'static str {
+        match self {
+            ArchiveLimitError::TooManyEntries { .. } => "max_entries",
+            ArchiveLimitError::TotalSizeExceeded { .. } => "max_total_size",
+            ArchiveLimitError::EntrySizeExceeded { .. } => "max_entry_size",
+        }
+    }
+}
+
+/// 解压单个流, 解压出的数据超过`max_total_size`时返回`ArchiveLimitError`
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub(super) fn copy_limited<R: std::io::Read, W: std::io::Write>(
+    decoder: R,
+    writer: &mut W,
+    limits: &ArchiveLimits,
+) -> anyhow::Result<u64> {
+    let limit = limits.max_total_size;
+    let copied = std::io::copy(&mut decoder.take(limit.saturating_add(1)), writer)?;
+    limits.check_total_size(copied)?;
+    Ok(copied)
+}
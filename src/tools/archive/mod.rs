@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+#[cfg(feature = "gzip")]
+pub mod gzip;
+#[cfg(any(feature = "tar", feature = "gzip", feature = "zstd"))]
+mod limits;
+#[cfg(feature = "tar")]
+pub mod tar;
+#[cfg(feature = "zstd")]
+pub mod zstd;
+
+#[cfg(any(feature = "tar", feature = "gzip", feature = "zstd"))]
+pub use limits::{ArchiveLimitError, ArchiveLimits};
+
+/// 从压缩包中读出的文件, 也可以直接用来生成新的压缩包
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub content: Vec<u8>,
+    pub last_modified: Option<SystemTime>,
+    /// Unix 权限, 如`0o755`
+    pub unix_permissions: Option<u32>,
+}
+
+impl ArchiveEntry {
+    pub fn new(name: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            content,
+            last_modified: None,
+            unix_permissions: None,
+        }
+    }
+}
+
+/// 生成压缩包, zip 和 tar 都实现了这个 trait
+pub trait ArchiveBuilder: Sized {
+    /// 写到任意`Write`, 返回底层的 writer
+    fn write_to<W: Write>(self, writer: W) -> Result<W>;
+
+    fn to_bytes(self) -> Result<Vec<u8>> {
+        self.write_to(Vec::new())
+    }
+
+    fn write_file(self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.write_to(std::io::BufWriter::new(file))?.flush()?;
+        Ok(())
+    }
+}
+
+/// 读取压缩包, zip 和 tar 都实现了这个 trait
+pub trait ArchiveExtractor {
+    /// 把所有文件读到内存中, 目录会被跳过
+    fn read_files(&mut self) -> Result<Vec<ArchiveEntry>>;
+
+    /// 解压到目录, 会恢复文件的修改时间和 Unix 权限
+    fn extract_to(&mut self, directory: &Path) -> Result<()>;
+}
+
+#[cfg(all(test, feature = "zip", feature = "tar"))]
+mod tests {
+    use super::tar::{TarBuilder, TarCompression, TarReader};
+    use super::*;
+    use crate::zip::{InnerZipFileInfo, ZipFileInfo, ZipReader};
+
+    #[test]
+    fn test_zip_to_tar() {
+        let zip = ZipFileInfo::new(vec![InnerZipFileInfo::new(
+            "a.txt".to_string(),
+            Some(b"a".to_vec()),
+        )
+        .with_unix_permissions(0o600)])
+        .to_bytes()
+        .unwrap();
+        let files = ZipReader::from_bytes(&zip).unwrap().read_files().unwrap();
+        let tar = files
+            .clone()
+            .into_iter()
+            .fold(TarBuilder::new(TarCompression::None), TarBuilder::add_entry)
+            .to_bytes()
+            .unwrap();
+        let tar_files = TarReader::from_bytes(&tar).unwrap().read_files().unwrap();
+        assert_eq!(tar_files, files);
+
+        let zip = ZipFileInfo::new(tar_files.into_iter().map(Into::into).collect())
+            .to_bytes()
+            .unwrap();
+        assert_eq!(
+            ZipReader::from_bytes(&zip).unwrap().read_files().unwrap(),
+            files
+        );
+    }
+}
@@ -0,0 +1,444 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use super::{ArchiveBuilder, ArchiveEntry, ArchiveExtractor, ArchiveLimits};
+
+/// 读到内存时最多预分配的大小, 声明的大小不可信
+const MAX_PREALLOCATE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// tar 包外层的压缩格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TarCompression {
+    /// 不压缩, `.tar`
+    #[default]
+    None,
+    /// `.tar.gz`
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// `.tar.zst`
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl TarCompression {
+    /// 按文件头的魔数识别压缩格式, 识别不出的当作不压缩
+    pub fn detect(header: &[u8]) -> Self {
+        #[cfg(feature = "gzip")]
+        if header.starts_with(&[0x1f, 0x8b]) {
+            return Self::Gzip;
+        }
+        #[cfg(feature = "zstd")]
+        if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Self::Zstd;
+        }
+        let _ = header;
+        Self::None
+    }
+}
+
+enum TarSource {
+    Entry(ArchiveEntry),
+    Path { name: String, path: PathBuf },
+}
+
+/// 生成 tar / tar.gz / tar.zst
+#[derive(Default)]
+pub struct TarBuilder {
+    files: Vec<TarSource>,
+    compression: TarCompression,
+}
+
+impl TarBuilder {
+    pub fn new(compression: TarCompression) -> Self {
+        Self {
+            files: Vec::new(),
+            compression,
+        }
+    }
+
+    /// 添加内存中的文件, 未设置修改时间时使用当前时间, 未设置权限时使用`0o644`
+    pub fn add_entry(mut self, entry: ArchiveEntry) -> Self {
+        self.files.push(TarSource::Entry(entry));
+        self
+    }
+
+    pub fn add_bytes(self, name: impl Into<String>, content: Vec<u8>) -> Self {
+        self.add_entry(ArchiveEntry::new(name, content))
+    }
+
+    /// 添加磁盘上的文件或目录(不递归), 修改时间和权限取自文件本身
+    pub fn add_path(mut self, name: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.files.push(TarSource::Path {
+            name: name.into(),
+            path: path.into(),
+        });
+        self
+    }
+
+    fn write_tar<W: Write>(self, writer: W) -> Result<W> {
+        let mut builder = ::tar::Builder::new(writer);
+        for file in self.files {
+            match file {
+                TarSource::Entry(entry) => {
+                    let mtime = entry
+                        .last_modified
+                        .unwrap_or_else(SystemTime::now)
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    let mut header = ::tar::Header::new_gnu();
+                    header.set_entry_type(::tar::EntryType::Regular);
+                    header.set_size(entry.content.len() as u64);
+                    header.set_mode(entry.unix_permissions.unwrap_or(0o644));
+                    header.set_mtime(mtime);
+                    builder.append_data(&mut header, &entry.name, entry.content.as_slice())?;
+                }
+                TarSource::Path { name, path } => {
+                    builder.append_path_with_name(path, name)?;
+                }
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+}
+
+impl ArchiveBuilder for TarBuilder {
+    fn write_to<W: Write>(self, writer: W) -> Result<W> {
+        match self.compression {
+            TarCompression::None => self.write_tar(writer),
+            #[cfg(feature = "gzip")]
+            TarCompression::Gzip => {
+                let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                Ok(self.write_tar(encoder)?.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            TarCompression::Zstd => {
+                let encoder = zstd::Encoder::new(writer, 0)?;
+                Ok(self.write_tar(encoder)?.finish()?)
+            }
+        }
+    }
+}
+
+/// 读取 tar / tar.gz / tar.zst, 只能读取一次
+///
+/// 读取和解压都受`ArchiveLimits`限制, 默认使用`ArchiveLimits::default()`.
+/// tar 中每个条目的大小就是实际存储的字节数, 读取前按条目头检查即可
+pub struct TarReader<'a> {
+    archive: ::tar::Archive<Box<dyn Read + 'a>>,
+    limits: ArchiveLimits,
+    consumed: bool,
+}
+
+impl TarReader<'static> {
+    /// 打开磁盘上的 tar 包, 自动识别压缩格式
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::detect(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<'a> TarReader<'a> {
+    pub fn new<R: Read + 'a>(reader: R, compression: TarCompression) -> Result<Self> {
+        let reader: Box<dyn Read + 'a> = match compression {
+            TarCompression::None => Box::new(reader),
+            #[cfg(feature = "gzip")]
+            TarCompression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            TarCompression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        };
+        let mut archive = ::tar::Archive::new(reader);
+        archive.set_preserve_mtime(true);
+        Ok(Self {
+            archive,
+            limits: ArchiveLimits::default(),
+            consumed: false,
+        })
+    }
+
+    /// 设置资源限制, 处理不可信的上传文件时应该按业务设置
+    pub fn with_limits(mut self, limits: ArchiveLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 按文件头自动识别压缩格式
+    pub fn detect<R: BufRead + 'a>(mut reader: R) -> Result<Self> {
+        let compression = TarCompression::detect(reader.fill_buf()?);
+        Self::new(reader, compression)
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        Self::new(bytes, TarCompression::detect(bytes))
+    }
+
+    fn consume(&mut self) -> Result<()> {
+        if self.consumed {
+            return Err(anyhow!("tar 包只能读取一次"));
+        }
+        self.consumed = true;
+        Ok(())
+    }
+}
+
+/// 按条目数, 条目大小和总大小检查, 返回条目的大小
+fn check_entry<R: Read>(
+    limits: &ArchiveLimits,
+    entry: &::tar::Entry<'_, R>,
+    entries: usize,
+    total_size: &mut u64,
+) -> Result<u64> {
+    limits.check_entries(entries)?;
+    let size = entry.size();
+    limits.check_entry(&entry.path()?.to_string_lossy(), size)?;
+    *total_size = total_size.saturating_add(size);
+    limits.check_total_size(*total_size)?;
+    Ok(size)
+}
+
+impl ArchiveExtractor for TarReader<'_> {
+    fn read_files(&mut self) -> Result<Vec<ArchiveEntry>> {
+        self.consume()?;
+        let mut files = Vec::new();
+        let mut total_size = 0u64;
+        for (i, entry) in self.archive.entries()?.enumerate() {
+            let mut entry = entry?;
+            let size = check_entry(&self.limits, &entry, i + 1, &mut total_size)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let last_modified = entry
+                .header()
+                .mtime()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            let unix_permissions = entry.header().mode().ok().map(|mode| mode & 0o7777);
+            let mut content = Vec::with_capacity(size.min(MAX_PREALLOCATE_SIZE) as usize);
+            entry.read_to_end(&mut content)?;
+            files.push(ArchiveEntry {
+                name,
+                content,
+                last_modified,
+                unix_permissions,
+            });
+        }
+        Ok(files)
+    }
+
+    /// 路径中带`..`或绝对路径的条目会被跳过. 文件和目录的权限只保留`0o777`部分,
+    /// 不会还原 setuid/setgid 等特殊位.
+    /// 超出限制时返回错误, 已经解压出的文件不会删除
+    fn extract_to(&mut self, directory: &Path) -> Result<()> {
+        self.consume()?;
+        std::fs::create_dir_all(directory)?;
+        let mut total_size = 0u64;
+        // 和`tar::Archive::unpack`一样, 目录最后再解压, 免得目录权限影响里面的文件
+        let mut directories = Vec::new();
+        for (i, entry) in self.archive.entries()?.enumerate() {
+            let mut entry = entry?;
+            check_entry(&self.limits, &entry, i + 1, &mut total_size)?;
+            if entry.header().entry_type().is_dir() {
+                directories.push(entry);
+            } else {
+                unpack_in(&mut entry, directory)?;
+            }
+        }
+        for mut entry in directories {
+            unpack_in(&mut entry, directory)?;
+        }
+        Ok(())
+    }
+}
+
+/// 解压一个条目, 普通文件和目录按条目头设置权限, 去掉特殊位
+fn unpack_in<R: Read>(entry: &mut ::tar::Entry<'_, R>, directory: &Path) -> Result<()> {
+    let entry_type = entry.header().entry_type();
+    let mode = entry.header().mode().ok();
+    // 和`Entry::unpack_in`一样去掉开头的`/`, 带`..`的条目不会解压
+    let path = directory.join(
+        entry
+            .path()?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect::<PathBuf>(),
+    );
+    if !entry.unpack_in(directory)? {
+        return Ok(());
+    }
+    if let Some(mode) = mode.filter(|_| entry_type.is_file() || entry_type.is_dir()) {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::ArchiveLimitError;
+
+    fn round_trip(compression: TarCompression) {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let bytes = TarBuilder::new(compression)
+            .add_bytes("a.txt", b"a".to_vec())
+            .add_entry(ArchiveEntry {
+                name: "dir/run.sh".to_string(),
+                content: "echo hello\n".repeat(100).into_bytes(),
+                last_modified: Some(mtime),
+                unix_permissions: Some(0o755),
+            })
+            .to_bytes()
+            .unwrap();
+        assert_eq!(TarCompression::detect(&bytes), compression);
+
+        let files = TarReader::from_bytes(&bytes).unwrap().read_files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "a.txt");
+        assert_eq!(files[0].content, b"a");
+        assert_eq!(files[0].unix_permissions, Some(0o644));
+        assert_eq!(files[1].name, "dir/run.sh");
+        assert_eq!(files[1].last_modified, Some(mtime));
+        assert_eq!(files[1].unix_permissions, Some(0o755));
+
+        let dir =
+            std::env::temp_dir().join(format!("tool-tar-{:?}-{}", compression, std::process::id()));
+        TarReader::from_bytes(&bytes)
+            .unwrap()
+            .extract_to(&dir)
+            .unwrap();
+        let path = dir.join("dir/run.sh");
+        assert_eq!(std::fs::read(&path).unwrap(), files[1].content);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_strips_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let bytes = TarBuilder::default()
+            .add_entry(ArchiveEntry {
+                name: "bin/su".to_string(),
+                content: b"#!/bin/sh\n".to_vec(),
+                last_modified: None,
+                unix_permissions: Some(0o4755),
+            })
+            .to_bytes()
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("tool-tar-setuid-{}", std::process::id()));
+        TarReader::from_bytes(&bytes)
+            .unwrap()
+            .extract_to(&dir)
+            .unwrap();
+        let mode = std::fs::metadata(dir.join("bin/su"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tar() {
+        round_trip(TarCompression::None);
+        #[cfg(feature = "gzip")]
+        round_trip(TarCompression::Gzip);
+        #[cfg(feature = "zstd")]
+        round_trip(TarCompression::Zstd);
+    }
+
+    #[test]
+    fn test_limits() {
+        let bytes = TarBuilder::default()
+            .add_bytes("a.txt", vec![b'a'; 100])
+            .add_bytes("b.txt", vec![b'b'; 100])
+            .to_bytes()
+            .unwrap();
+        let limit_name = |limits: ArchiveLimits| {
+            let err = TarReader::from_bytes(&bytes)
+                .unwrap()
+                .with_limits(limits)
+                .read_files()
+                .err()
+                .unwrap();
+            err.downcast::<ArchiveLimitError>().unwrap().limit_name()
+        };
+        let limits = ArchiveLimits::default();
+        assert_eq!(
+            limit_name(ArchiveLimits {
+                max_entries: 1,
+                ..limits
+            }),
+            "max_entries"
+        );
+        assert_eq!(
+            limit_name(ArchiveLimits {
+                max_entry_size: 99,
+                ..limits
+            }),
+            "max_entry_size"
+        );
+        assert_eq!(
+            limit_name(ArchiveLimits {
+                max_total_size: 150,
+                ..limits
+            }),
+            "max_total_size"
+        );
+
+        let dir = std::env::temp_dir().join(format!("tool-tar-limits-{}", std::process::id()));
+        let err = TarReader::from_bytes(&bytes)
+            .unwrap()
+            .with_limits(ArchiveLimits {
+                max_total_size: 150,
+                ..limits
+            })
+            .extract_to(&dir)
+            .err()
+            .unwrap();
+        assert!(err.is::<ArchiveLimitError>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_multi_member_gzip() {
+        let tar = TarBuilder::default()
+            .add_bytes("a.txt", b"a".to_vec())
+            .to_bytes()
+            .unwrap();
+        // 分成两个 gzip member, 如`cat a.gz b.gz`
+        let (head, tail) = tar.split_at(tar.len() / 2);
+        let mut bytes = crate::archive::gzip::gzip_compress(head).unwrap();
+        bytes.extend(crate::archive::gzip::gzip_compress(tail).unwrap());
+        let files = TarReader::from_bytes(&bytes).unwrap().read_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].content, b"a");
+    }
+
+    #[test]
+    fn test_read_once() {
+        let bytes = TarBuilder::default()
+            .add_bytes("a.txt", b"a".to_vec())
+            .to_bytes()
+            .unwrap();
+        let mut reader = TarReader::from_bytes(&bytes).unwrap();
+        reader.read_files().unwrap();
+        assert!(reader.read_files().is_err());
+    }
+}
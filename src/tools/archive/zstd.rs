@@ -0,0 +1,82 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::limits::copy_limited;
+use super::ArchiveLimits;
+
+/// zstd 默认压缩等级
+pub const DEFAULT_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// zstd 压缩, 流式处理, 返回底层的 writer. `level`为 1 ~ 22, 0 表示默认等级
+pub fn zstd_compress_stream<R: Read, W: Write>(mut reader: R, writer: W, level: i32) -> Result<W> {
+    let mut encoder = zstd::Encoder::new(writer, level)?;
+    std::io::copy(&mut reader, &mut encoder)?;
+    Ok(encoder.finish()?)
+}
+
+/// zstd 解压, 流式处理, 返回底层的 writer. 不限制解压后的大小, 处理不可信的数据时用
+/// `zstd_decompress_stream_with_limits`
+pub fn zstd_decompress_stream<R: Read, W: Write>(reader: R, mut writer: W) -> Result<W> {
+    let mut decoder = zstd::Decoder::new(reader)?;
+    std::io::copy(&mut decoder, &mut writer)?;
+    Ok(writer)
+}
+
+/// zstd 解压, 解压出的数据超过`limits.max_total_size`时返回`ArchiveLimitError`
+pub fn zstd_decompress_stream_with_limits<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    limits: &ArchiveLimits,
+) -> Result<W> {
+    let decoder = zstd::Decoder::new(reader)?;
+    copy_limited(decoder, &mut writer, limits)?;
+    Ok(writer)
+}
+
+pub fn zstd_compress(data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+    zstd_compress_stream(data.as_ref(), Vec::new(), DEFAULT_LEVEL)
+}
+
+/// 解压到内存, 受`ArchiveLimits::default()`限制
+pub fn zstd_decompress(data: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+    zstd_decompress_stream_with_limits(data.as_ref(), Vec::new(), &ArchiveLimits::default())
+}
+
+/// 压缩单个文件, 如`app.log` -> `app.log.zst`
+pub fn zstd_compress_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let reader = std::fs::File::open(src)?;
+    let writer = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    zstd_compress_stream(reader, writer, DEFAULT_LEVEL)?.flush()?;
+    Ok(())
+}
+
+pub fn zstd_decompress_file(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let reader = std::fs::File::open(src)?;
+    let writer = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    zstd_decompress_stream(reader, writer)?.flush()?;
+    Ok(())
+}
+
+#[test]
+fn zstd_round_trip_test() {
+    let data = "zstd ".repeat(1000);
+    let compressed = zstd_compress(&data).unwrap();
+    assert!(compressed.len() < data.len());
+    assert_eq!(zstd_decompress(compressed).unwrap(), data.as_bytes());
+}
+
+#[test]
+fn zstd_limits_test() {
+    let compressed = zstd_compress(vec![0u8; 1024 * 1024]).unwrap();
+    let limits = ArchiveLimits {
+        max_total_size: 1024,
+        ..Default::default()
+    };
+    let err = zstd_decompress_stream_with_limits(compressed.as_slice(), Vec::new(), &limits)
+        .err()
+        .unwrap();
+    let err = err.downcast::<super::ArchiveLimitError>().unwrap();
+    assert_eq!(err.limit_name(), "max_total_size");
+}
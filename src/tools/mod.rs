@@ -1,3 +1,5 @@
+#[cfg(any(feature = "zip", feature = "tar", feature = "gzip", feature = "zstd"))]
+pub mod archive;
 #[cfg(feature = "crypto")]
 pub mod crypto;
 #[cfg(feature = "database")]
@@ -7,6 +7,11 @@ use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature = "session_breaker")]
+use crate::sre_breaker::{breaker::SreBreaker, call::Breaker, group::BreakerGroup};
+#[cfg(feature = "session_breaker")]
+use reqwest::{IntoUrl, Method, Request, RequestBuilder, Response};
+
 const STORE_COOKIE_PATH: &str = "spider.cookie";
 const USER_AGENT_NAME: &str = "User-Agent";
 const USER_AGENT_VALUE:&str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/114.0.0.0 Safari/537.36";
@@ -127,6 +132,160 @@ impl Session {
     }
 }
 
+#[cfg(feature = "session_breaker")]
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    /// 这个 host 的熔断器正在丢弃请求, 请求没有发出
+    #[error("session: host {0} is unavailable, request dropped by breaker")]
+    HostUnavailable(String),
+    /// 开启熔断时 url 必须有 host, 否则没法区分下游
+    #[error("session: url {0} has no host")]
+    NoHost(String),
+    /// 按模板创建这个 host 的熔断器失败, 请求没有发出
+    #[error("session: no breaker for host {host}: {source:#}")]
+    Breaker { host: String, source: anyhow::Error },
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+#[cfg(feature = "session_breaker")]
+impl Session {
+    /// 按 host 熔断, 每个`host:port`一个熔断器, 见`BreakerSession`
+    pub fn with_breaker(self, breakers: BreakerGroup<SreBreaker>) -> BreakerSession {
+        BreakerSession {
+            session: self,
+            breakers,
+        }
+    }
+}
+
+/// 按 host 熔断的`Session`, 由`Session::with_breaker`创建
+///
+/// 不能解引用成`Client`, 所有请求都经过熔断器. 5xx, 超时和连接错误算作失败,
+/// host 被熔断时请求不会发出, 直接返回`SessionError::HostUnavailable`
+#[cfg(feature = "session_breaker")]
+pub struct BreakerSession {
+    session: Session,
+    breakers: BreakerGroup<SreBreaker>,
+}
+
+#[cfg(feature = "session_breaker")]
+impl BreakerSession {
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> BreakerRequest<'_> {
+        BreakerRequest {
+            session: self,
+            builder: self.session.client.request(method, url),
+        }
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> BreakerRequest<'_> {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> BreakerRequest<'_> {
+        self.request(Method::POST, url)
+    }
+
+    pub fn put(&self, url: impl IntoUrl) -> BreakerRequest<'_> {
+        self.request(Method::PUT, url)
+    }
+
+    pub fn delete(&self, url: impl IntoUrl) -> BreakerRequest<'_> {
+        self.request(Method::DELETE, url)
+    }
+
+    pub fn head(&self, url: impl IntoUrl) -> BreakerRequest<'_> {
+        self.request(Method::HEAD, url)
+    }
+
+    /// 经过熔断器发送请求, url 没有 host 时返回`SessionError::NoHost`
+    pub async fn execute(&self, request: Request) -> Result<Response, SessionError> {
+        let url = request.url();
+        let Some(host) = url.host_str() else {
+            return Err(SessionError::NoHost(url.to_string()));
+        };
+        let host = match url.port_or_known_default() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        let breaker = self
+            .breakers
+            .get(&host)
+            .map_err(|source| SessionError::Breaker {
+                host: host.clone(),
+                source,
+            })?;
+        breaker
+            .call_with(self.session.client.execute(request), is_host_healthy)
+            .await
+            .map_err(|err| match err.into_inner() {
+                Some(err) => SessionError::Request(err),
+                None => SessionError::HostUnavailable(host),
+            })
+    }
+
+    /// 获取 cookie
+    pub fn gey_cookie(&self) -> cookie_store::CookieStore {
+        self.session.gey_cookie()
+    }
+
+    /// 加载`cookie`
+    pub fn load_cookie(&self, cookie_store: cookie_store::CookieStore) {
+        self.session.load_cookie(cookie_store)
+    }
+
+    /// 清除`cookie`
+    pub fn clear_cookie(&self) {
+        self.session.clear_cookie()
+    }
+
+    pub fn get_store_cookie_path(&self) -> &str {
+        self.session.get_store_cookie_path()
+    }
+
+    pub fn save_cookie(&self) -> AnyResult<()> {
+        self.session.save_cookie()
+    }
+}
+
+/// 经过熔断器发送的请求, 见`BreakerSession::request`
+#[cfg(feature = "session_breaker")]
+pub struct BreakerRequest<'a> {
+    session: &'a BreakerSession,
+    builder: RequestBuilder,
+}
+
+#[cfg(feature = "session_breaker")]
+impl BreakerRequest<'_> {
+    /// 修改请求, 如设置 header, 参数和 body, `f`中的用法和`RequestBuilder`一样
+    pub fn map(mut self, f: impl FnOnce(RequestBuilder) -> RequestBuilder) -> Self {
+        self.builder = f(self.builder);
+        self
+    }
+
+    pub fn headers(self, headers: HeaderMap) -> Self {
+        self.map(|builder| builder.headers(headers))
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.map(|builder| builder.timeout(timeout))
+    }
+
+    /// 经过熔断器发送, 见`BreakerSession::execute`
+    pub async fn send(self) -> Result<Response, SessionError> {
+        self.session.execute(self.builder.build()?).await
+    }
+}
+
+/// 只有 host 本身的问题才算失败, 4xx, 重定向和解析错误都不算
+#[cfg(feature = "session_breaker")]
+fn is_host_healthy(result: &Result<Response, reqwest::Error>) -> bool {
+    match result {
+        Ok(resp) => !resp.status().is_server_error(),
+        Err(err) => !(err.is_timeout() || err.is_connect()),
+    }
+}
+
 impl Deref for Session {
     type Target = Client;
 
@@ -143,3 +302,93 @@ pub fn get_head_map() -> HeaderMap {
     );
     header
 }
+
+#[cfg(all(test, feature = "session_breaker"))]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::breaker::SreBreakerBuilder;
+    use crate::sre_breaker::clock::MockClock;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// 对每个连接都返回`status`
+    fn serve(status: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 1024]);
+                let resp = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    fn session() -> (BreakerSession, Arc<MockClock>) {
+        let clock = MockClock::new();
+        let mut template = SreBreakerBuilder::default();
+        template.clock(clock.clone()).seed(0);
+        let breakers = BreakerGroup::new(template, Duration::from_secs(60)).unwrap();
+        (Session::default().with_breaker(breakers), clock)
+    }
+
+    /// 发送`n`次请求, 返回被熔断的次数. `get`和`execute`交替使用, 都要经过熔断器
+    async fn shed(session: &BreakerSession, clock: &MockClock, url: &str, n: usize) -> usize {
+        let timeout = Duration::from_millis(100);
+        let mut shed = 0;
+        for i in 0..n {
+            let result = if i % 2 == 0 {
+                session.get(url).timeout(timeout).send().await
+            } else {
+                let mut request = Request::new(Method::GET, url.parse().unwrap());
+                *request.timeout_mut() = Some(timeout);
+                session.execute(request).await
+            };
+            if matches!(result, Err(SessionError::HostUnavailable(_))) {
+                shed += 1;
+            }
+            clock.advance(Duration::from_millis(100));
+        }
+        shed
+    }
+
+    #[tokio::test]
+    async fn test_session_breaker() {
+        let (session, clock) = session();
+        // 5xx 算失败
+        let down = serve(500);
+        assert!(shed(&session, &clock, &down, 30).await > 10);
+        // 4xx 不算失败, 也不受其他 host 影响
+        let ok = serve(404);
+        assert_eq!(shed(&session, &clock, &ok, 30).await, 0);
+
+        // 超时算失败, 监听但不接受连接, 请求一直等不到响应
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hang = format!("http://{}/", listener.local_addr().unwrap());
+        assert!(shed(&session, &clock, &hang, 30).await > 10);
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_connect_error() {
+        let (session, _) = session();
+        // 端口 0 不能连接, 总是连接错误
+        let err = session.get("http://127.0.0.1:0/").send().await.unwrap_err();
+        let SessionError::Request(err) = err else {
+            panic!("{err}");
+        };
+        assert!(err.is_connect());
+        assert!(!is_host_healthy(&Err(err)));
+    }
+
+    #[tokio::test]
+    async fn test_no_host() {
+        let (session, _) = session();
+        let url = reqwest::Url::parse("data:text/plain,hello").unwrap();
+        let result = session.execute(Request::new(Method::GET, url)).await;
+        assert!(matches!(result, Err(SessionError::NoHost(_))));
+    }
+}
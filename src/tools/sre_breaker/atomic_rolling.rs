@@ -0,0 +1,306 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+
+use super::clock::{system_clock, Clock};
+use super::rolling::{validate_window, DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION};
+
+/// 无锁的滑动窗口计数器, 统计结果和`RollingPolicy`相同
+///
+/// 每个桶是一个`AtomicU64`, 高 24 位是所属的时间片(从创建开始第几个桶), 中间 20 位是值之和,
+/// 低 20 位是次数, 值之和与次数总是一起更新, 统计时不会读到一半的写入.
+/// 写入时时间片不一致说明桶已经过期, 用 CAS 直接换成新的时间片, 不需要加锁重置.
+/// 每个分片的桶最多记 1048575 次, 超过后这个桶不再计数.
+/// 时间片按 24 位回绕, 桶的时长为 1 毫秒时约 4.6 小时回绕一次, 100 毫秒时约 19 天
+///
+/// 多个线程同时写入时可以分片, 每个线程固定写其中一片, 统计时把所有分片加起来
+///
+/// 收益在多核多线程同时写入时, 没有争用时和`RollingPolicy`差别不大,
+/// 所以熔断器默认用`RollingPolicy`, 见`SreBreakerBuilder::shards`.
+/// 用`cargo bench --bench sre_breaker --features sre_breaker`可以在本机对比
+#[derive(Debug)]
+pub struct AtomicRollingPolicy {
+    shards: Vec<Shard>,
+    size: usize,
+    bucket_duration: Duration,
+    start: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+/// 一个分片的所有桶, 按缓存行对齐, 不同分片的写入不会互相影响
+#[derive(Debug)]
+#[repr(align(64))]
+struct Shard {
+    buckets: Vec<AtomicBucket>,
+}
+
+/// 时间片的位数
+const EPOCH_BITS: u32 = 24;
+const EPOCH_MASK: u32 = (1 << EPOCH_BITS) - 1;
+/// 值之和与次数各占的位数
+const COUNT_BITS: u32 = 20;
+const COUNT_MAX: u64 = (1 << COUNT_BITS) - 1;
+
+/// 打包好的 (时间片, 值之和, 次数)
+#[derive(Debug, Default)]
+pub(super) struct AtomicBucket(AtomicU64);
+
+impl AtomicBucket {
+    /// 时间片一致时累加, 否则换成新的时间片重新计数
+    pub(super) fn add(&self, epoch: u32, val: u64) {
+        add_tagged(&self.0, epoch, val);
+    }
+
+    /// 还在窗口内并且不是当前桶时返回 (值之和, 次数), 否则是 (0, 0)
+    pub(super) fn load(&self, epoch: u32, size: usize) -> (u64, u64) {
+        count_in_window(self.0.load(Ordering::Relaxed), epoch, size)
+    }
+
+    pub(super) fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for AtomicRollingPolicy {
+    fn default() -> Self {
+        AtomicRollingPolicy::new(DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION).unwrap()
+    }
+}
+
+impl AtomicRollingPolicy {
+    /// 不分片, 写入不多时用这个就够了
+    pub fn new(size: usize, bucket_duration: Duration) -> Result<AtomicRollingPolicy> {
+        AtomicRollingPolicy::with_shards(size, bucket_duration, 1)
+    }
+
+    /// 分成`shards`片, 为 0 时按 CPU 核数分片
+    pub fn with_shards(
+        size: usize,
+        bucket_duration: Duration,
+        shards: usize,
+    ) -> Result<AtomicRollingPolicy> {
+        validate_window(size, bucket_duration)?;
+        let shards = resolve_shards(shards);
+        ensure!(shards <= 1024, "分片太多, 当前为 {shards}");
+        let clock = system_clock();
+        Ok(AtomicRollingPolicy {
+            shards: (0..shards)
+                .map(|_| Shard {
+                    buckets: (0..size).map(|_| AtomicBucket::default()).collect(),
+                })
+                .collect(),
+            size,
+            bucket_duration,
+            start: clock.now(),
+            clock,
+        })
+    }
+
+    /// 换成`clock`计时, 窗口从`clock`的当前时间开始
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> AtomicRollingPolicy {
+        self.start = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 往当前的桶中添加一个值
+    pub fn add(&self, val: u64) {
+        let epoch = self.epoch();
+        let shard = &self.shards[shard_index(self.shards.len())];
+        shard.buckets[epoch as usize % self.size].add(epoch, val);
+    }
+
+    /// 统计当前桶之外, 还在窗口内的桶的总和, 返回 (值之和, 次数)
+    pub fn summary(&self) -> (u64, u64) {
+        let epoch = self.epoch();
+        let mut accept = 0u64;
+        let mut total = 0u64;
+        for shard in &self.shards {
+            for bucket in &shard.buckets {
+                let (sum, count) = bucket.load(epoch, self.size);
+                accept += sum;
+                total += count;
+            }
+        }
+        (accept, total)
+    }
+
+    /// 实际的分片数
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// 整个窗口的时长
+    pub fn window_span(&self) -> Duration {
+        self.bucket_duration * self.size as u32
+    }
+
+    /// 清空所有桶, 和并发的`add`同时进行时, 那次写入可能被保留也可能被清掉
+    pub fn reset(&self) {
+        for shard in &self.shards {
+            for bucket in &shard.buckets {
+                bucket.reset();
+            }
+        }
+    }
+
+    fn epoch(&self) -> u32 {
+        epoch(self.start, self.clock.now(), self.bucket_duration)
+    }
+}
+
+/// `now`是从`start`开始的第几个桶, 即当前时间片, 只保留低 24 位
+pub(super) fn epoch(start: Instant, now: Instant, bucket_duration: Duration) -> u32 {
+    let elapsed = now.duration_since(start);
+    (elapsed.as_nanos() / bucket_duration.as_nanos()) as u32 & EPOCH_MASK
+}
+
+/// 分片数为 0 时换成 CPU 核数
+pub fn resolve_shards(shards: usize) -> usize {
+    match shards {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+fn pack(epoch: u32, sum: u64, total: u64) -> u64 {
+    (epoch as u64) << (2 * COUNT_BITS) | sum << COUNT_BITS | total
+}
+
+fn unpack(value: u64) -> (u32, u64, u64) {
+    (
+        (value >> (2 * COUNT_BITS)) as u32,
+        value >> COUNT_BITS & COUNT_MAX,
+        value & COUNT_MAX,
+    )
+}
+
+/// 时间片一致时累加, 否则换成新的时间片重新计数. 次数已满时丢弃这次写入
+fn add_tagged(cell: &AtomicU64, epoch: u32, val: u64) {
+    let mut current = cell.load(Ordering::Relaxed);
+    loop {
+        let (tag, sum, total) = unpack(current);
+        let new = if tag == epoch {
+            if total == COUNT_MAX {
+                return;
+            }
+            pack(epoch, (sum + val.min(COUNT_MAX)).min(COUNT_MAX), total + 1)
+        } else {
+            pack(epoch, val.min(COUNT_MAX), 1)
+        };
+        match cell.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// 时间片在 [epoch - size + 1, epoch - 1] 之间的 (值之和, 次数), 即排除当前桶和已经滑出窗口的桶.
+/// 全 0 的值是从来没写过或者被重置的桶, 计数也是 0
+fn count_in_window(value: u64, epoch: u32, size: usize) -> (u64, u64) {
+    let (tag, sum, total) = unpack(value);
+    let age = (epoch.wrapping_sub(tag) & EPOCH_MASK) as usize;
+    if age == 0 || age >= size {
+        return (0, 0);
+    }
+    (sum, total)
+}
+
+/// 当前线程写哪个分片, 每个线程第一次写入时轮流分配
+pub(super) fn shard_index(shards: usize) -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+    if shards == 1 {
+        return 0;
+    }
+    INDEX.with(|index| {
+        let i = index.get().unwrap_or_else(|| {
+            let i = NEXT.fetch_add(1, Ordering::Relaxed);
+            index.set(Some(i));
+            i
+        });
+        i % shards
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+    use crate::sre_breaker::rolling::RollingPolicy;
+    use std::thread;
+
+    #[test]
+    fn test_same_as_rolling_policy() {
+        let clock = MockClock::new();
+        let duration = Duration::from_millis(40);
+        let locked = RollingPolicy::new(5, duration)
+            .unwrap()
+            .with_clock(clock.clone());
+        let atomic = AtomicRollingPolicy::new(5, duration)
+            .unwrap()
+            .with_clock(clock.clone());
+        for round in 0..8u64 {
+            for i in 0..10 {
+                locked.add((i + round) % 2);
+                atomic.add((i + round) % 2);
+            }
+            assert_eq!(atomic.summary(), locked.summary());
+            clock.advance(duration * (round as u32 % 3));
+        }
+        clock.advance(duration * 6);
+        assert_eq!(atomic.summary(), (0, 0));
+        assert_eq!(locked.summary(), (0, 0));
+    }
+
+    #[test]
+    fn test_sharded() {
+        let policy = AtomicRollingPolicy::with_shards(10, Duration::from_millis(50), 4).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        policy.add(i % 2);
+                    }
+                });
+            }
+        });
+        thread::sleep(Duration::from_millis(60));
+        // 线程可能跨过桶的边界, 只要都还在窗口内, 总数就不会变
+        assert_eq!(policy.summary(), (2000, 4000));
+        policy.reset();
+        assert_eq!(policy.summary(), (0, 0));
+    }
+
+    #[test]
+    fn test_count_in_window() {
+        assert_eq!(count_in_window(pack(9, 2, 3), 10, 5), (2, 3));
+        assert_eq!(count_in_window(pack(10, 2, 3), 10, 5), (0, 0));
+        assert_eq!(count_in_window(pack(5, 2, 3), 10, 5), (0, 0));
+        assert_eq!(count_in_window(pack(EPOCH_MASK, 2, 3), 1, 5), (2, 3));
+        assert_eq!(count_in_window(0, 10, 5), (0, 0));
+    }
+
+    #[test]
+    fn test_add_tagged() {
+        let cell = AtomicU64::new(0);
+        add_tagged(&cell, 3, 1);
+        add_tagged(&cell, 3, 0);
+        assert_eq!(unpack(cell.load(Ordering::Relaxed)), (3, 1, 2));
+        // 换时间片时重新计数
+        add_tagged(&cell, 4, 0);
+        assert_eq!(unpack(cell.load(Ordering::Relaxed)), (4, 0, 1));
+        // 次数满了之后不再计数, 值之和不会超过次数
+        cell.store(pack(4, COUNT_MAX - 1, COUNT_MAX), Ordering::Relaxed);
+        add_tagged(&cell, 4, 1);
+        assert_eq!(
+            unpack(cell.load(Ordering::Relaxed)),
+            (4, COUNT_MAX - 1, COUNT_MAX)
+        );
+    }
+}
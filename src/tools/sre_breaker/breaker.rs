@@ -1,5 +1,12 @@
-use super::rolling::RollingPolicy;
-use rand::{self, random};
+use super::atomic_rolling::{resolve_shards, AtomicRollingPolicy};
+use super::clock::{system_clock, Clock, Rng};
+use super::latency::{LatencyStats, LatencyWindow};
+use super::rolling::{validate_window, RollingPolicy, DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION};
+use parking_lot::RwLock;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -10,61 +17,286 @@ pub enum Error {
 }
 
 #[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 /// google sre 弹性熔断器
 pub struct SreBreaker {
     /// 系数, 熔断触发范围
     #[builder(default = "1f64 / 0.9f64")]
     k: f64,
-    /// 滑动窗口
-    #[builder(setter(skip), default = "RollingPolicy::default()")]
-    policy: RollingPolicy,
+    /// 滑动窗口的桶数量
+    #[builder(default = "DEFAULT_BUCKETS")]
+    buckets: usize,
+    /// 每个桶的时长, 低 QPS 的下游可以调大, 让窗口内有足够的请求
+    #[builder(default = "DEFAULT_BUCKET_DURATION")]
+    bucket_duration: Duration,
+    /// 滑动窗口的分片数, 见`SreBreakerBuilder::shards`
+    #[builder(
+        setter(custom),
+        field(
+            ty = "Option<usize>",
+            build = "resolve_shards(self.shards.unwrap_or(1))"
+        )
+    )]
+    shards: usize,
+    /// 时钟, 见`SreBreakerBuilder::clock`
+    #[builder(setter(custom), default = "system_clock()")]
+    clock: Arc<dyn Clock>,
+    /// 滑动窗口, 和熔断器使用同一个时钟
+    #[builder(
+        setter(skip),
+        default = "Policy::new(self.buckets.unwrap_or(DEFAULT_BUCKETS), self.bucket_duration.unwrap_or(DEFAULT_BUCKET_DURATION), resolve_shards(self.shards.unwrap_or(1))).map_err(|e| e.to_string())?.with_clock(self.clock.clone().unwrap_or_else(system_clock))"
+    )]
+    pub(super) policy: Policy,
+    /// 决定是否拒绝用的随机数, 见`SreBreakerBuilder::seed`
+    #[builder(setter(custom), default)]
+    rng: Rng,
 
     /// 请求数小于这个数字, 直接忽略
     #[builder(default = "5")]
     requests: u64,
+
+    /// 耗时不小于这个值的调用算慢调用, 默认不检测
+    #[builder(setter(strip_option), default)]
+    slow_call_threshold: Option<Duration>,
+    /// 窗口内慢调用的比例不低于这个值(0 ~ 1)时, 成功的慢调用也算作失败
+    #[builder(default = "0.5")]
+    slow_call_ratio: f64,
+    /// 滑动窗口的耗时统计, 和`policy`使用同样的窗口和时钟. 只在配置了`slow_call_threshold`时创建
+    #[builder(setter(skip), default = "self.latency_window()?")]
+    latency: Option<LatencyWindow>,
+
+    /// 上次`allow`时是否在拒绝请求
+    #[builder(setter(skip), default)]
+    rejecting: AtomicBool,
+    #[builder(setter(skip), default)]
+    listeners: Listeners,
+    /// 累计执行降级逻辑的次数
+    #[builder(setter(skip), default)]
+    fallbacks: AtomicU64,
+}
+
+/// 熔断器的统计快照
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SreStats {
+    /// 窗口内成功的请求数
+    pub accepted: u64,
+    /// 窗口内的总请求数, 包括被拒绝的
+    pub total: u64,
+    /// 当前拒绝请求的概率, 0 ~ 1
+    pub drop_ratio: f64,
+    /// 统计窗口的时长
+    pub window: Duration,
+    /// 累计执行降级逻辑的次数, 不随窗口滑动清零
+    pub fallbacks: u64,
+    /// 滑动窗口的分片数, 1 表示不分片
+    pub shards: usize,
+}
+
+/// 熔断器的滑动窗口, 默认是加锁的`RollingPolicy`, 分片时换成无锁的`AtomicRollingPolicy`
+#[derive(Debug)]
+pub(super) enum Policy {
+    Locked(RollingPolicy),
+    Atomic(AtomicRollingPolicy),
+}
+
+impl Policy {
+    fn new(buckets: usize, bucket_duration: Duration, shards: usize) -> anyhow::Result<Policy> {
+        Ok(match shards {
+            1 => Policy::Locked(RollingPolicy::new(buckets, bucket_duration)?),
+            n => Policy::Atomic(AtomicRollingPolicy::with_shards(
+                buckets,
+                bucket_duration,
+                n,
+            )?),
+        })
+    }
+
+    fn with_clock(self, clock: Arc<dyn Clock>) -> Policy {
+        match self {
+            Policy::Locked(policy) => Policy::Locked(policy.with_clock(clock)),
+            Policy::Atomic(policy) => Policy::Atomic(policy.with_clock(clock)),
+        }
+    }
+
+    fn add(&self, val: u64) {
+        match self {
+            Policy::Locked(policy) => policy.add(val),
+            Policy::Atomic(policy) => policy.add(val),
+        }
+    }
+
+    pub(super) fn summary(&self) -> (u64, u64) {
+        match self {
+            Policy::Locked(policy) => policy.summary(),
+            Policy::Atomic(policy) => policy.summary(),
+        }
+    }
+}
+
+/// 熔断器开始或停止拒绝请求
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerEvent {
+    StartRejecting(SreStats),
+    StopRejecting(SreStats),
+}
+
+type Listener = Arc<dyn Fn(&BreakerEvent) + Send + Sync>;
+
+#[derive(Default)]
+struct Listeners(RwLock<Vec<Listener>>);
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Listeners({})", self.0.read().len())
+    }
 }
 
 impl Default for SreBreaker {
     fn default() -> Self {
         // 默认请求拒绝率小于 0.11 就不触发熔断
-        SreBreaker::new(1f64 / 0.9f64, 5)
+        SreBreaker::new(1f64 / 0.9f64, 5, DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION).unwrap()
+    }
+}
+
+impl SreBreakerBuilder {
+    /// 滑动窗口的分片数, 默认 1, 用加锁的`RollingPolicy`. 大于 1 时换成无锁的`AtomicRollingPolicy`,
+    /// 为 0 时按 CPU 核数分片. 多核多线程高 QPS 时分片可以减少争用, 耗时统计按同样的分片数分片
+    pub fn shards(&mut self, shards: usize) -> &mut Self {
+        self.shards = Some(shards);
+        self
+    }
+
+    /// 熔断器用的时钟, 滑动窗口和耗时统计都用它计时, 默认是系统时钟
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// 用固定的种子初始化随机数, 同样的请求序列会得到同样的拒绝结果
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.rng = Some(Rng::seeded(seed));
+        self
+    }
+
+    /// 配置了`slow_call_threshold`时才创建耗时统计, 直方图占用的内存不小
+    fn latency_window(&self) -> Result<Option<LatencyWindow>, String> {
+        if self.slow_call_threshold.flatten().is_none() {
+            return Ok(None);
+        }
+        let window = LatencyWindow::with_shards(
+            self.buckets.unwrap_or(DEFAULT_BUCKETS),
+            self.bucket_duration.unwrap_or(DEFAULT_BUCKET_DURATION),
+            resolve_shards(self.shards.unwrap_or(1)),
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(Some(window.with_clock(
+            self.clock.clone().unwrap_or_else(system_clock),
+        )))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0f64..=1f64).contains(&self.slow_call_ratio.unwrap_or(0f64)) {
+            return Err("慢调用比例必须在 0 ~ 1 之间".to_string());
+        }
+        validate_window(
+            self.buckets.unwrap_or(DEFAULT_BUCKETS),
+            self.bucket_duration.unwrap_or(DEFAULT_BUCKET_DURATION),
+        )
+        .map_err(|e| e.to_string())
     }
 }
 
 impl SreBreaker {
-    pub fn new(k: f64, requests: u64) -> SreBreaker {
-        SreBreaker {
+    /// 窗口为`buckets`个`bucket_duration`, 窗口配置不合法时返回错误
+    pub fn new(
+        k: f64,
+        requests: u64,
+        buckets: usize,
+        bucket_duration: Duration,
+    ) -> anyhow::Result<SreBreaker> {
+        Ok(SreBreaker {
             k,
             requests,
-            policy: RollingPolicy::default(),
-        }
+            buckets,
+            bucket_duration,
+            shards: 1,
+            clock: system_clock(),
+            policy: Policy::new(buckets, bucket_duration, 1)?,
+            rng: Rng::default(),
+            slow_call_threshold: None,
+            slow_call_ratio: 0.5,
+            latency: None,
+            rejecting: AtomicBool::new(false),
+            listeners: Listeners::default(),
+            fallbacks: AtomicU64::new(0),
+        })
     }
 
     pub fn allow(&self) -> Result<(), Error> {
+        let stats = self.stats();
+        self.notify(stats);
+        if stats.drop_ratio > 0f64 && self.true_on_proba(stats.drop_ratio) {
+            return Err(Error::CircuitOpenError);
+        }
+        Ok(())
+    }
+
+    /// 熔断器时钟的当前时间
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// 当前的统计快照
+    pub fn stats(&self) -> SreStats {
         // 获取一段时间内的所有请求数和接受的请求数
-        let (accept, req_total) = self.policy.summary();
+        let (accepted, total) = self.policy.summary();
+        SreStats {
+            accepted,
+            total,
+            drop_ratio: self.drop_ratio(accepted, total),
+            window: self.bucket_duration * self.buckets as u32,
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+            shards: self.shards,
+        }
+    }
+
+    /// 注册回调, 熔断器开始或停止拒绝请求时调用. 状态在`allow`时才会更新.
+    /// 回调在锁外执行, 回调里可以再调用`on_event`
+    pub fn on_event(&self, listener: impl Fn(&BreakerEvent) + Send + Sync + 'static) {
+        self.listeners.0.write().push(Arc::new(listener));
+    }
+
+    fn drop_ratio(&self, accept: u64, req_total: u64) -> f64 {
         // 接收的请求数乘上我们设置的系数, 用这个来代替总的接收数量
         let requests = self.k * accept as f64;
         // 如果请求数不足, 或者总的接收数量大于总请求数, 不会触发熔断
         if req_total < self.requests || (req_total as f64) < requests {
-            return Ok(());
+            return 0f64;
         }
         // dr 越大, 说明被拒绝的请求越多, 那么越容易触发熔断
-        let dr = 0f64.max(((req_total as f64) - requests) / ((req_total + 1) as f64));
-        // 在配置的接受范围之内, 不会触发熔断
-        if dr < 0f64 {
-            return Ok(());
+        0f64.max(((req_total as f64) - requests) / ((req_total + 1) as f64))
+    }
+
+    fn notify(&self, stats: SreStats) {
+        let rejecting = stats.drop_ratio > 0f64;
+        if self.rejecting.swap(rejecting, Ordering::Relaxed) == rejecting {
+            return;
         }
-        if self.true_on_proba(dr) {
-            return Err(Error::CircuitOpenError);
+        let event = if rejecting {
+            BreakerEvent::StartRejecting(stats)
+        } else {
+            BreakerEvent::StopRejecting(stats)
+        };
+        let listeners = self.listeners.0.read().clone();
+        for listener in listeners {
+            listener(&event);
         }
-        Ok(())
     }
 
     fn true_on_proba(&self, proba: f64) -> bool {
         // 随机生成一个 0 ~ 1 之间的小数,
         // 如果 proba 越大, 随机数小于它的概率就越大
-        random::<f64>() < proba
+        self.rng.next_f64() < proba
     }
 
     pub fn mark_success(&self) {
@@ -74,48 +306,237 @@ impl SreBreaker {
     pub fn mark_failed(&self) {
         self.policy.add(0);
     }
+
+    /// 记录一次降级, 见`Fallback`
+    pub fn mark_fallback(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录结果和耗时. 配置了`slow_call_threshold`时, 如果窗口内慢调用的比例不低于
+    /// `slow_call_ratio`, 成功的慢调用也算作失败. 请求数不足`requests`时不计算比例
+    pub fn mark_with_latency(&self, success: bool, latency: Duration) {
+        let slow = self
+            .slow_call_threshold
+            .is_some_and(|threshold| latency >= threshold);
+        if let Some(window) = &self.latency {
+            window.record(latency, slow);
+        }
+        if success && !(slow && self.too_many_slow_calls()) {
+            self.mark_success();
+        } else {
+            self.mark_failed();
+        }
+    }
+
+    /// 窗口内的耗时分位数和慢调用数, 只统计通过`mark_with_latency`或`call`系列方法记录的调用.
+    /// 没有配置`slow_call_threshold`时不统计, 返回`LatencyStats::default()`
+    pub fn latency(&self) -> LatencyStats {
+        self.latency
+            .as_ref()
+            .map(LatencyWindow::stats)
+            .unwrap_or_default()
+    }
+
+    fn too_many_slow_calls(&self) -> bool {
+        let Some(window) = &self.latency else {
+            return false;
+        };
+        let (slow, total) = window.slow_summary();
+        total >= self.requests && slow as f64 >= self.slow_call_ratio * total as f64
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
+    use crate::sre_breaker::clock::MockClock;
     use std::time::Duration;
 
+    const BUCKET: Duration = DEFAULT_BUCKET_DURATION;
+
+    fn breaker(seed: u64) -> (SreBreaker, Arc<MockClock>) {
+        let clock = MockClock::new();
+        let breaker = SreBreakerBuilder::default()
+            .clock(clock.clone())
+            .seed(seed)
+            .build()
+            .unwrap();
+        (breaker, clock)
+    }
+
+    fn record(breaker: &SreBreaker, success: u64, failed: u64) {
+        for _ in 0..success {
+            breaker.mark_success();
+        }
+        for _ in 0..failed {
+            breaker.mark_failed();
+        }
+    }
+
     #[test]
-    fn test_rang() {
-        let i = random::<f64>();
-        println!("{}", i);
+    fn test_drop_ratio() {
+        let (breaker, clock) = breaker(1);
+        // 请求数不足时不拒绝
+        record(&breaker, 0, 4);
+        clock.advance(BUCKET);
+        assert_eq!(breaker.stats().drop_ratio, 0.0);
+        assert!((0..100).all(|_| breaker.allow().is_ok()));
+
+        // 成功率 90% 以上不拒绝
+        record(&breaker, 96, 0);
+        clock.advance(BUCKET);
+        assert_eq!((breaker.stats().accepted, breaker.stats().total), (96, 100));
+        assert_eq!(breaker.stats().drop_ratio, 0.0);
+
+        // (200 - 96 * 10 / 9) / 201
+        record(&breaker, 0, 100);
+        clock.advance(BUCKET);
+        let stats = breaker.stats();
+        assert_eq!((stats.accepted, stats.total), (96, 200));
+        assert!((stats.drop_ratio - (200.0 - 96.0 / 0.9) / 201.0).abs() < 1e-9);
     }
 
     #[test]
     fn test_sre() {
-        let mut breaker = SreBreaker::default();
-        println!("breaker = {breaker:?}");
-        for i in 0..1000 {
-            match breaker.allow() {
-                Ok(_) => {
-                    println!("allow");
-                }
-                Err(err) => {
-                    println!("err = {err}, breaker = {breaker:?}");
-                    breaker.mark_failed();
-                    continue;
-                }
-            }
+        let (breaker, clock) = breaker(42);
+        assert_eq!(breaker.now(), clock.now());
+        record(&breaker, 0, 100);
+        clock.advance(BUCKET);
+        let stats = breaker.stats();
+        let rejected = (0..1000).filter(|_| breaker.allow().is_err()).count();
+        // 拒绝的比例接近 drop_ratio
+        assert!((rejected as f64 / 1000.0 - stats.drop_ratio).abs() < 0.05);
+
+        // 窗口滑过之后恢复
+        clock.advance(BUCKET * 10);
+        assert!((0..100).all(|_| breaker.allow().is_ok()));
+    }
 
-            thread::sleep(Duration::from_millis(1));
-            if random::<f64>() > 0.8f64 {
-                breaker.mark_failed();
-            } else {
-                breaker.mark_success();
+    #[test]
+    fn test_seed_reproducible() {
+        let decisions = |seed| {
+            let (breaker, clock) = breaker(seed);
+            record(&breaker, 50, 50);
+            clock.advance(BUCKET);
+            (0..200)
+                .map(|_| breaker.allow().is_ok())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(decisions(7), decisions(7));
+        assert_ne!(decisions(7), decisions(8));
+    }
+
+    #[test]
+    fn test_stats_and_events() {
+        let (breaker, clock) = breaker(0);
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        breaker.on_event({
+            let events = events.clone();
+            move |event| events.lock().push(*event)
+        });
+        record(&breaker, 0, 100);
+        assert_eq!(breaker.stats().total, 0);
+        // 当前桶不参与统计
+        clock.advance(BUCKET);
+        let stats = breaker.stats();
+        assert_eq!((stats.accepted, stats.total), (0, 100));
+        assert!(stats.drop_ratio > 0.99);
+        assert_eq!(stats.window, Duration::from_secs(1));
+
+        let _ = breaker.allow();
+        let _ = breaker.allow();
+        // 窗口滑过后不再拒绝
+        clock.advance(Duration::from_secs(1));
+        assert!(breaker.allow().is_ok());
+        let events = events.lock();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], BreakerEvent::StartRejecting(s) if s.total == 100));
+        assert!(matches!(events[1], BreakerEvent::StopRejecting(s) if s.total == 0));
+    }
+
+    #[test]
+    fn test_subscribe_in_listener() {
+        let (breaker, clock) = breaker(0);
+        let breaker = Arc::new(breaker);
+        let weak = Arc::downgrade(&breaker);
+        breaker.on_event(move |_| {
+            if let Some(breaker) = weak.upgrade() {
+                breaker.on_event(|_| {});
             }
+        });
+        record(&breaker, 0, 100);
+        clock.advance(BUCKET);
+        let _ = breaker.allow();
+        assert_eq!(format!("{:?}", breaker.listeners), "Listeners(2)");
+    }
+
+    #[test]
+    fn test_slow_call() {
+        let clock = MockClock::new();
+        let breaker = SreBreakerBuilder::default()
+            .clock(clock.clone())
+            .slow_call_threshold(Duration::from_secs(1))
+            .slow_call_ratio(0.5)
+            .build()
+            .unwrap();
+        // 慢调用比例不够时仍然算成功
+        for ms in [10, 2_000, 20, 30] {
+            breaker.mark_with_latency(true, Duration::from_millis(ms));
         }
+        clock.advance(BUCKET);
+        breaker.mark_with_latency(true, Duration::from_secs(3));
+        clock.advance(BUCKET);
+        assert_eq!((breaker.stats().accepted, breaker.stats().total), (5, 5));
+        let latency = breaker.latency();
+        assert_eq!((latency.slow, latency.total), (2, 5));
+
+        // 超过一半是慢调用后, 成功的慢调用算失败, 快的调用不受影响
+        breaker.mark_with_latency(true, Duration::from_secs(2));
+        breaker.mark_with_latency(true, Duration::from_secs(2));
+        clock.advance(BUCKET);
+        breaker.mark_with_latency(true, Duration::from_secs(2));
+        breaker.mark_with_latency(true, Duration::from_millis(10));
+        clock.advance(BUCKET);
+        assert_eq!((breaker.stats().accepted, breaker.stats().total), (8, 9));
+        assert!(breaker.latency().p50 >= Duration::from_secs(2));
+
+        // 不检测慢调用时不统计耗时
+        let breaker = SreBreakerBuilder::default().build().unwrap();
+        assert!(breaker.latency.is_none());
+        breaker.mark_with_latency(true, Duration::from_secs(2));
+        assert_eq!(breaker.latency(), LatencyStats::default());
+        assert!(SreBreakerBuilder::default()
+            .slow_call_ratio(1.5)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_window_config() {
+        let breaker = SreBreakerBuilder::default()
+            .buckets(60)
+            .bucket_duration(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        assert_eq!(breaker.stats().window, Duration::from_secs(60));
+        let err = SreBreakerBuilder::default().buckets(0).build().unwrap_err();
+        assert!(err.to_string().contains("2 个桶"));
+        assert!(SreBreaker::new(1.5, 5, 10, Duration::ZERO).is_err());
+        assert_eq!(breaker.stats().shards, 1);
+        assert!(matches!(breaker.policy, Policy::Locked(_)));
+        let sharded = SreBreakerBuilder::default().shards(4).build().unwrap();
+        assert_eq!(sharded.stats().shards, 4);
+        assert!(matches!(sharded.policy, Policy::Atomic(_)));
+        let expected = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let by_cpu = SreBreakerBuilder::default().shards(0).build().unwrap();
+        assert_eq!(by_cpu.stats().shards, expected);
     }
 
     #[test]
     fn test_sre_builder() {
         let breaker = SreBreakerBuilder::default().build().unwrap();
-        println!("breaker = {breaker:?}");
+        assert_eq!(breaker.k, 1f64 / 0.9f64);
+        assert_eq!(breaker.requests, 5);
+        assert_eq!(breaker.stats().window, Duration::from_secs(1));
     }
 }
@@ -0,0 +1,185 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BulkheadError {
+    /// 并发数满了, 排队的请求也满了
+    #[error("bulkhead: queue is full")]
+    QueueFull,
+    /// 排队超过了`queue_timeout`
+    #[error("bulkhead: queue wait timeout")]
+    Timeout,
+}
+
+#[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+/// 舱壁隔离: 限制一个下游同时进行的调用数, 超过时排队, 队列满了或者等太久就直接返回错误.
+/// 慢下游只会占满自己的并发数, 不会拖垮整个服务
+pub struct Bulkhead {
+    /// 最大并发数
+    #[builder(default = "10")]
+    max_concurrent: usize,
+    /// 最多排队多少个请求, 为 0 时不排队
+    #[builder(default = "0")]
+    max_queue: usize,
+    /// 排队的最长时间, 默认一直等
+    #[builder(setter(strip_option), default)]
+    queue_timeout: Option<Duration>,
+
+    #[builder(
+        setter(skip),
+        default = "Semaphore::new(self.max_concurrent.unwrap_or(10))"
+    )]
+    semaphore: Semaphore,
+    #[builder(setter(skip), default)]
+    queued: AtomicUsize,
+    #[builder(setter(skip), default)]
+    accepted: AtomicU64,
+    #[builder(setter(skip), default)]
+    rejected: AtomicU64,
+    #[builder(setter(skip), default)]
+    timed_out: AtomicU64,
+}
+
+/// 舱壁的统计快照
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkheadStats {
+    /// 正在进行的调用数
+    pub in_flight: usize,
+    /// 正在排队的请求数
+    pub queued: usize,
+    pub max_concurrent: usize,
+    pub max_queue: usize,
+    /// 累计放行的请求数
+    pub accepted: u64,
+    /// 累计因为队列满了被拒绝的请求数
+    pub rejected: u64,
+    /// 累计排队超时的请求数
+    pub timed_out: u64,
+}
+
+impl Default for Bulkhead {
+    fn default() -> Self {
+        BulkheadBuilder::default().build().unwrap()
+    }
+}
+
+impl BulkheadBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent == Some(0) {
+            return Err("最大并发数不能为 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Bulkhead {
+    /// 获取一个并发名额, 返回的`BulkheadPermit`释放后名额归还
+    pub async fn acquire(&self) -> Result<BulkheadPermit<'_>, BulkheadError> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Ok(self.accept(permit));
+        }
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queue {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(BulkheadError::QueueFull);
+        }
+        // 排队中的请求被取消时也要减掉
+        let _queued = Queued(&self.queued);
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.semaphore.acquire()).await,
+            None => Ok(self.semaphore.acquire().await),
+        };
+        match permit {
+            Ok(permit) => Ok(self.accept(permit.expect("semaphore 不会被关闭"))),
+            Err(_) => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(BulkheadError::Timeout)
+            }
+        }
+    }
+
+    /// 拿到名额后执行`fut`
+    pub async fn call<F: Future>(&self, fut: F) -> Result<F::Output, BulkheadError> {
+        let _permit = self.acquire().await?;
+        Ok(fut.await)
+    }
+
+    pub fn stats(&self) -> BulkheadStats {
+        BulkheadStats {
+            in_flight: self.max_concurrent - self.semaphore.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            max_concurrent: self.max_concurrent,
+            max_queue: self.max_queue,
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+
+    fn accept<'a>(&'a self, permit: SemaphorePermit<'a>) -> BulkheadPermit<'a> {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        BulkheadPermit { _permit: permit }
+    }
+}
+
+/// 并发名额, 释放时归还
+#[derive(Debug)]
+pub struct BulkheadPermit<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bulkhead() {
+        let bulkhead = BulkheadBuilder::default()
+            .max_concurrent(1)
+            .max_queue(1)
+            .queue_timeout(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let permit = bulkhead.acquire().await.unwrap();
+        // 第一个排队, 第二个发现队列满了
+        let (waiting, full) = tokio::join!(bulkhead.acquire(), async {
+            tokio::task::yield_now().await;
+            assert_eq!(bulkhead.stats().queued, 1);
+            bulkhead.acquire().await
+        });
+        assert_eq!(waiting.unwrap_err(), BulkheadError::Timeout);
+        assert_eq!(full.unwrap_err(), BulkheadError::QueueFull);
+
+        // 排队时名额释放了
+        let (waiting, _) = tokio::join!(bulkhead.call(async { 1 }), async { drop(permit) });
+        assert_eq!(waiting, Ok(1));
+        assert_eq!(
+            bulkhead.stats(),
+            BulkheadStats {
+                in_flight: 0,
+                queued: 0,
+                max_concurrent: 1,
+                max_queue: 1,
+                accepted: 2,
+                rejected: 1,
+                timed_out: 1,
+            }
+        );
+        assert!(BulkheadBuilder::default()
+            .max_concurrent(0)
+            .build()
+            .is_err());
+    }
+}
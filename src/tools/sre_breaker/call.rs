@@ -0,0 +1,335 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::breaker::{Error, SreBreaker};
+
+/// 通过熔断器调用时的错误, 区分被熔断器拒绝和调用本身的错误
+#[derive(Debug, thiserror::Error)]
+pub enum CallError<E> {
+    /// 熔断器拒绝了请求, 调用没有执行
+    #[error("circuitbreaker: not allowed for circuit open")]
+    Rejected,
+    /// 没有可用的熔断器, 比如从`BreakerGroup`按模板创建失败, 调用没有执行
+    #[error("circuitbreaker: unavailable: {0:#}")]
+    Unavailable(anyhow::Error),
+    /// 调用本身返回的错误
+    #[error(transparent)]
+    Inner(E),
+}
+
+impl<E> CallError<E> {
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, CallError::Rejected)
+    }
+
+    /// 调用本身返回的错误, 调用没有执行时返回`None`
+    pub fn into_inner(self) -> Option<E> {
+        match self {
+            CallError::Inner(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// 默认的结果分类: `Ok`算成功, `Err`算失败
+pub(super) fn is_ok<T, E>(result: &Result<T, E>) -> bool {
+    result.is_ok()
+}
+
+/// `Breaker::allow`放行时发的凭证, 记录结果时原样交回.
+/// 熔断器用它分辨结果属于哪一轮放行的请求, `SreBreaker`不区分
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Permit {
+    pub(super) generation: u64,
+}
+
+/// 熔断器, 不同的下游可以选择不同的熔断算法
+///
+/// 除了手动调用`allow`和`mark_success`/`mark_failed`, 更推荐用`call`系列方法,
+/// 不会漏掉失败分支
+#[allow(async_fn_in_trait)]
+pub trait Breaker {
+    /// 是否允许请求通过, 通过时返回记录结果要用的凭证
+    fn allow(&self) -> Result<Permit, Error>;
+
+    fn mark_success(&self, permit: Permit);
+
+    fn mark_failed(&self, permit: Permit);
+
+    /// 请求被`allow`拒绝后调用, 默认什么都不做
+    fn mark_rejected(&self) {}
+
+    /// 执行了降级逻辑后调用, 默认什么都不做
+    fn mark_fallback(&self) {}
+
+    /// 当前时间, `call`系列方法用它计算耗时. 熔断器有自己的时钟时应该用它的时钟,
+    /// 这样换成`MockClock`后慢调用的判断也跟着变
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// 记录一次调用的结果和耗时, `call`系列方法会自动调用. 默认忽略耗时
+    fn mark_with_latency(&self, permit: Permit, success: bool, _latency: Duration) {
+        if success {
+            self.mark_success(permit);
+        } else {
+            self.mark_failed(permit);
+        }
+    }
+
+    /// 先检查熔断器, 通过后执行`fut`, 按结果自动记录成功或失败.
+    /// 被拒绝时返回`CallError::Rejected`
+    async fn call<F, T, E>(&self, fut: F) -> Result<T, CallError<E>>
+    where
+        Self: Sized,
+        F: Future<Output = Result<T, E>>,
+    {
+        self.call_with(fut, is_ok).await
+    }
+
+    /// 和`call`一样, 但用`is_success`判断结果算不算成功,
+    /// 比如把 4xx 这种调用方自己的错误也算作成功, 不计入熔断
+    async fn call_with<F, T, E, P>(&self, fut: F, is_success: P) -> Result<T, CallError<E>>
+    where
+        Self: Sized,
+        F: Future<Output = Result<T, E>>,
+        P: FnOnce(&Result<T, E>) -> bool,
+    {
+        let start = before_call(self)?;
+        let result = fut.await;
+        after_call(self, start, result, is_success)
+    }
+
+    /// `call`的同步版本
+    fn call_sync<F, T, E>(&self, f: F) -> Result<T, CallError<E>>
+    where
+        Self: Sized,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.call_sync_with(f, is_ok)
+    }
+
+    /// `call_with`的同步版本
+    fn call_sync_with<F, T, E, P>(&self, f: F, is_success: P) -> Result<T, CallError<E>>
+    where
+        Self: Sized,
+        F: FnOnce() -> Result<T, E>,
+        P: FnOnce(&Result<T, E>) -> bool,
+    {
+        let start = before_call(self)?;
+        let result = f();
+        after_call(self, start, result, is_success)
+    }
+}
+
+/// 通过时返回凭证和调用开始的时间
+pub(super) fn before_call<B: Breaker, E>(breaker: &B) -> Result<(Permit, Instant), CallError<E>> {
+    match breaker.allow() {
+        Ok(permit) => Ok((permit, breaker.now())),
+        Err(_) => {
+            breaker.mark_rejected();
+            Err(CallError::Rejected)
+        }
+    }
+}
+
+pub(super) fn after_call<B, T, E, P>(
+    breaker: &B,
+    (permit, start): (Permit, Instant),
+    result: Result<T, E>,
+    is_success: P,
+) -> Result<T, CallError<E>>
+where
+    B: Breaker,
+    P: FnOnce(&Result<T, E>) -> bool,
+{
+    breaker.mark_with_latency(
+        permit,
+        is_success(&result),
+        breaker.now().saturating_duration_since(start),
+    );
+    result.map_err(CallError::Inner)
+}
+
+impl Breaker for SreBreaker {
+    fn allow(&self) -> Result<Permit, Error> {
+        SreBreaker::allow(self).map(|_| Permit::default())
+    }
+
+    fn mark_success(&self, _permit: Permit) {
+        SreBreaker::mark_success(self)
+    }
+
+    fn mark_failed(&self, _permit: Permit) {
+        SreBreaker::mark_failed(self)
+    }
+
+    /// 被拒绝的请求也计入总请求数, 这是 google sre 算法的要求
+    fn mark_rejected(&self) {
+        SreBreaker::mark_failed(self)
+    }
+
+    fn mark_with_latency(&self, _permit: Permit, success: bool, latency: Duration) {
+        SreBreaker::mark_with_latency(self, success, latency)
+    }
+
+    fn mark_fallback(&self) {
+        SreBreaker::mark_fallback(self)
+    }
+
+    fn now(&self) -> Instant {
+        SreBreaker::now(self)
+    }
+}
+
+macro_rules! impl_breaker_for_pointer {
+    ($($pointer:ident),*) => {
+        $(
+            impl<B: Breaker + ?Sized> Breaker for $pointer<B> {
+                fn allow(&self) -> Result<Permit, Error> {
+                    (**self).allow()
+                }
+
+                fn mark_success(&self, permit: Permit) {
+                    (**self).mark_success(permit)
+                }
+
+                fn mark_failed(&self, permit: Permit) {
+                    (**self).mark_failed(permit)
+                }
+
+                fn mark_rejected(&self) {
+                    (**self).mark_rejected()
+                }
+
+                fn mark_with_latency(&self, permit: Permit, success: bool, latency: Duration) {
+                    (**self).mark_with_latency(permit, success, latency)
+                }
+
+                fn mark_fallback(&self) {
+                    (**self).mark_fallback()
+                }
+
+                fn now(&self) -> Instant {
+                    (**self).now()
+                }
+            }
+        )*
+    };
+}
+
+impl_breaker_for_pointer!(Box, Arc);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::breaker::SreBreakerBuilder;
+    use crate::sre_breaker::clock::MockClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn breaker() -> (SreBreaker, Arc<MockClock>) {
+        let clock = MockClock::new();
+        let breaker = SreBreakerBuilder::default()
+            .clock(clock.clone())
+            .seed(0)
+            .build()
+            .unwrap();
+        (breaker, clock)
+    }
+
+    /// 当前桶不参与统计, 走到下一个桶
+    fn next_bucket(clock: &MockClock) {
+        clock.advance(Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_call_sync() {
+        let (breaker, clock) = breaker();
+        assert_eq!(breaker.call_sync(|| Ok::<_, String>(1)).unwrap(), 1);
+        let err = breaker.call_sync(|| Err::<(), _>("boom")).unwrap_err();
+        assert!(!err.is_rejected());
+        assert_eq!(err.into_inner(), Some("boom"));
+        next_bucket(&clock);
+        assert_eq!(breaker.policy.summary(), (1, 2));
+    }
+
+    #[test]
+    fn test_call_rejected() {
+        let (breaker, clock) = breaker();
+        for _ in 0..100 {
+            let _ = breaker.call_sync(|| Err::<(), _>("boom"));
+        }
+        next_bucket(&clock);
+        let executed = AtomicUsize::new(0);
+        let rejected = (0..20)
+            .filter(|_| {
+                breaker
+                    .call_sync(|| {
+                        executed.fetch_add(1, Ordering::Relaxed);
+                        Ok::<_, ()>(())
+                    })
+                    .is_err_and(|err| err.is_rejected())
+            })
+            .count();
+        // 全部失败时拒绝的概率约为 99%
+        assert!(rejected > 10);
+        assert_eq!(executed.load(Ordering::Relaxed), 20 - rejected);
+    }
+
+    #[tokio::test]
+    async fn test_call_with_predicate() {
+        let (breaker, clock) = breaker();
+        // 4xx 是调用方的问题, 不算下游失败
+        let is_success = |result: &Result<u16, u16>| match result {
+            Ok(_) => true,
+            Err(status) => (400..500).contains(status),
+        };
+        for status in [404, 500, 503] {
+            let err = breaker
+                .call_with(async { Err::<u16, _>(status) }, is_success)
+                .await
+                .unwrap_err();
+            assert_eq!(err.into_inner(), Some(status));
+        }
+        assert_eq!(
+            breaker.call(async { Ok::<_, u16>(200) }).await.unwrap(),
+            200
+        );
+        next_bucket(&clock);
+        assert_eq!(breaker.policy.summary(), (2, 4));
+    }
+
+    #[test]
+    fn test_call_slow() {
+        let clock = MockClock::new();
+        let breaker = SreBreakerBuilder::default()
+            .clock(clock.clone())
+            .requests(0)
+            .slow_call_threshold(Duration::from_millis(5))
+            .slow_call_ratio(0.0)
+            .build()
+            .unwrap();
+        let slow = || {
+            clock.advance(Duration::from_millis(10));
+            Ok::<_, ()>(())
+        };
+        // 调用本身成功, 但耗时超过阈值, 计为失败
+        assert!(breaker.call_sync(slow).is_ok());
+        assert!(breaker.call_sync(|| Ok::<_, ()>(())).is_ok());
+        next_bucket(&clock);
+        assert_eq!(breaker.policy.summary(), (1, 2));
+        assert_eq!(breaker.latency().slow, 1);
+    }
+
+    #[tokio::test]
+    async fn test_call_in_spawn() {
+        let breaker = Arc::new(SreBreaker::default());
+        let handle = tokio::spawn({
+            let breaker = breaker.clone();
+            async move { breaker.call(async { Ok::<_, ()>(1) }).await }
+        });
+        assert_eq!(handle.await.unwrap().unwrap(), 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng};
+
+/// 时钟, 默认是`SystemClock`. 测试时换成`MockClock`, 手动推进时间
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// 系统时钟
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 默认的时钟
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// 只有调用`advance`时才会走的时钟
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Arc<MockClock> {
+        Arc::new(MockClock::default())
+    }
+
+    /// 时间向前走`duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
+
+/// 熔断器用的随机数, 默认用线程本地的随机数, 不加锁. 指定种子时结果可以复现, 这时才需要加锁
+#[derive(Debug, Default)]
+pub(super) enum Rng {
+    #[default]
+    Thread,
+    Seeded(Box<Mutex<StdRng>>),
+}
+
+impl Clone for Rng {
+    fn clone(&self) -> Self {
+        match self {
+            Rng::Thread => Rng::Thread,
+            Rng::Seeded(rng) => Rng::Seeded(Box::new(Mutex::new(rng.lock().clone()))),
+        }
+    }
+}
+
+impl Rng {
+    pub(super) fn seeded(seed: u64) -> Self {
+        Rng::Seeded(Box::new(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    /// 0 ~ 1 之间的随机数
+    pub(super) fn next_f64(&self) -> f64 {
+        match self {
+            Rng::Thread => rand::thread_rng().gen::<f64>(),
+            Rng::Seeded(rng) => rng.lock().gen::<f64>(),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use super::call::{is_ok, Breaker, CallError};
+
+/// 带降级逻辑的熔断器调用: 被熔断器拒绝时(开启`on_failure`后调用失败时也是)
+/// 执行`fallback`, 比如返回缓存, 默认值或者走降级接口. `fallback`收到的是原本要返回的错误
+///
+/// 每次执行降级都会调用`Breaker::mark_fallback`, `SreBreaker`会计入`SreStats::fallbacks`,
+/// `ThreeStateBreaker`会计入`ThreeStateStats::fallbacks`
+pub struct Fallback<B, F> {
+    breaker: Arc<B>,
+    fallback: F,
+    on_failure: bool,
+}
+
+impl<B: Breaker, F> Fallback<B, F> {
+    /// 默认只在被拒绝时降级
+    pub fn new(breaker: Arc<B>, fallback: F) -> Self {
+        Fallback {
+            breaker,
+            fallback,
+            on_failure: false,
+        }
+    }
+
+    /// 调用本身失败时也降级
+    pub fn on_failure(mut self, on_failure: bool) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// 通过熔断器执行`fut`, 需要时执行返回`Future`的降级逻辑
+    pub async fn call<Fut, T, E, FbFut>(&self, fut: Fut) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        F: Fn(CallError<E>) -> FbFut,
+        FbFut: Future<Output = Result<T, E>>,
+    {
+        self.call_with(fut, is_ok).await
+    }
+
+    /// 和`call`一样, 但用`is_success`判断结果算不算成功, 见`Breaker::call_with`
+    pub async fn call_with<Fut, T, E, P, FbFut>(&self, fut: Fut, is_success: P) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        P: FnOnce(&Result<T, E>) -> bool,
+        F: Fn(CallError<E>) -> FbFut,
+        FbFut: Future<Output = Result<T, E>>,
+    {
+        match self.breaker.call_with(fut, is_success).await {
+            Err(err) if self.should_fallback(&err) => {
+                self.breaker.mark_fallback();
+                (self.fallback)(err).await
+            }
+            result => result.map_err(|err| err.into_inner().expect("调用没有执行时总会降级")),
+        }
+    }
+
+    /// `call`的同步版本, 降级逻辑直接返回结果
+    pub fn call_sync<T, E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<T, E>
+    where
+        F: Fn(CallError<E>) -> Result<T, E>,
+    {
+        self.call_sync_with(f, is_ok)
+    }
+
+    /// `call_with`的同步版本
+    pub fn call_sync_with<T, E, P>(
+        &self,
+        f: impl FnOnce() -> Result<T, E>,
+        is_success: P,
+    ) -> Result<T, E>
+    where
+        P: FnOnce(&Result<T, E>) -> bool,
+        F: Fn(CallError<E>) -> Result<T, E>,
+    {
+        match self.breaker.call_sync_with(f, is_success) {
+            Err(err) if self.should_fallback(&err) => {
+                self.breaker.mark_fallback();
+                (self.fallback)(err)
+            }
+            result => result.map_err(|err| err.into_inner().expect("调用没有执行时总会降级")),
+        }
+    }
+
+    /// 调用没有执行时总是降级
+    fn should_fallback<E>(&self, err: &CallError<E>) -> bool {
+        !matches!(err, CallError::Inner(_)) || self.on_failure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::breaker::{SreBreaker, SreBreakerBuilder};
+    use crate::sre_breaker::clock::MockClock;
+    use crate::sre_breaker::three_state::{State, ThreeStateBreaker, TripPolicy};
+    use std::time::Duration;
+
+    /// 连续失败后几乎所有请求都会被拒绝
+    fn tripped() -> Arc<SreBreaker> {
+        let clock = MockClock::new();
+        let breaker = SreBreakerBuilder::default()
+            .clock(clock.clone())
+            .seed(0)
+            .build()
+            .unwrap();
+        for _ in 0..100 {
+            breaker.mark_failed();
+        }
+        clock.advance(Duration::from_millis(100));
+        Arc::new(breaker)
+    }
+
+    #[tokio::test]
+    async fn test_fallback_on_rejected() {
+        let breaker = tripped();
+        let cached = Fallback::new(breaker.clone(), |err: CallError<&'static str>| async move {
+            assert!(err.is_rejected());
+            Ok("cached")
+        });
+        let mut fallbacks = 0;
+        for _ in 0..20 {
+            let result = cached.call(async { Ok("fresh") }).await.unwrap();
+            fallbacks += (result == "cached") as u64;
+        }
+        assert!(fallbacks > 10);
+        assert_eq!(breaker.stats().fallbacks, fallbacks);
+    }
+
+    #[test]
+    fn test_fallback_on_failure() {
+        let breaker = Arc::new(SreBreaker::default());
+        let default = |_| Ok::<_, &str>(0);
+        let fallback = Fallback::new(breaker.clone(), default);
+        assert_eq!(fallback.call_sync(|| Err("boom")), Err("boom"));
+        assert_eq!(fallback.call_sync(|| Ok(1)), Ok(1));
+        assert_eq!(breaker.stats().fallbacks, 0);
+
+        let fallback = fallback.on_failure(true);
+        assert_eq!(fallback.call_sync(|| Err("boom")), Ok(0));
+        assert_eq!(breaker.stats().fallbacks, 1);
+    }
+
+    #[test]
+    fn test_fallback_three_state() {
+        let breaker = Arc::new(
+            ThreeStateBreaker::new(
+                TripPolicy::ConsecutiveFailures(2),
+                Duration::from_secs(10),
+                1,
+            )
+            .unwrap(),
+        );
+        let fallback = Fallback::new(breaker.clone(), |_| Ok::<_, u16>(0));
+        // 404 是调用方的问题, 不计入熔断
+        let not_server_error = |result: &Result<u16, u16>| result.is_ok_and(|code| code < 500);
+        for _ in 0..3 {
+            assert_eq!(
+                fallback.call_sync_with(|| Ok(404), not_server_error),
+                Ok(404)
+            );
+        }
+        assert_eq!(breaker.stats().consecutive_failures, 0);
+
+        for _ in 0..2 {
+            assert_eq!(
+                fallback.call_sync_with(|| Ok(503), not_server_error),
+                Ok(503)
+            );
+        }
+        assert_eq!(fallback.call_sync_with(|| Ok(200), not_server_error), Ok(0));
+        let stats = breaker.stats();
+        assert_eq!((stats.state, stats.fallbacks), (State::Open, 1));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::hash_map::{self, DefaultHasher};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use parking_lot::RwLock;
+
+use super::breaker::{SreBreaker, SreBreakerBuilder};
+use super::clock::{system_clock, Clock};
+use super::three_state::{ThreeStateBreaker, ThreeStateBreakerBuilder};
+
+/// 分片数量, 不同分片的 key 互不影响
+const SHARDS: usize = 16;
+
+/// 创建熔断器的模板, 熔断器的 builder 和`Fn() -> anyhow::Result<B>`都可以当模板
+pub trait BreakerTemplate: Send + Sync {
+    type Breaker;
+
+    fn create(&self) -> Result<Self::Breaker>;
+}
+
+impl<B, F> BreakerTemplate for F
+where
+    F: Fn() -> Result<B> + Send + Sync,
+{
+    type Breaker = B;
+
+    fn create(&self) -> Result<B> {
+        self()
+    }
+}
+
+impl BreakerTemplate for SreBreakerBuilder {
+    type Breaker = SreBreaker;
+
+    fn create(&self) -> Result<SreBreaker> {
+        Ok(self.build()?)
+    }
+}
+
+impl BreakerTemplate for ThreeStateBreakerBuilder {
+    type Breaker = ThreeStateBreaker;
+
+    fn create(&self) -> Result<ThreeStateBreaker> {
+        Ok(self.build()?)
+    }
+}
+
+type Template<B> = Box<dyn BreakerTemplate<Breaker = B>>;
+
+/// 按 key (如 host, 数据库分片, 队列名) 隔离的一组熔断器, 第一次用到时按模板创建,
+/// 可以给个别 key 单独配置. 超过`idle_timeout`没用过的 key 会被清理, 内存只和活跃的 key 数量有关.
+/// 模板在`new`和`with_override`时先创建一次, 配置有误时在这里返回错误.
+/// 闭包模板之后仍然可能失败, 这时`get`返回错误
+pub struct BreakerGroup<B> {
+    template: Template<B>,
+    overrides: HashMap<String, Template<B>>,
+    idle_timeout: Duration,
+    shards: Vec<RwLock<Shard<B>>>,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+}
+
+struct Shard<B> {
+    entries: HashMap<String, Entry<B>>,
+    /// 上次清理的时间, 距离`start`的毫秒数
+    last_sweep: u64,
+}
+
+struct Entry<B> {
+    breaker: Arc<B>,
+    /// 上次使用的时间, 距离`start`的毫秒数
+    last_used: AtomicU64,
+}
+
+impl<B> BreakerGroup<B> {
+    /// `template`用来创建所有没有单独配置的 key 的熔断器
+    pub fn new(
+        template: impl BreakerTemplate<Breaker = B> + 'static,
+        idle_timeout: Duration,
+    ) -> Result<Self> {
+        template.create()?;
+        let clock = system_clock();
+        Ok(BreakerGroup {
+            template: Box::new(template),
+            overrides: HashMap::new(),
+            idle_timeout,
+            shards: (0..SHARDS)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        entries: HashMap::new(),
+                        last_sweep: 0,
+                    })
+                })
+                .collect(),
+            start: clock.now(),
+            clock,
+        })
+    }
+
+    /// 换成`clock`判断 key 是否过期, 只影响过期清理, 熔断器自己的时钟在模板中设置
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.start = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 给`key`单独配置模板
+    pub fn with_override(
+        mut self,
+        key: impl Into<String>,
+        template: impl BreakerTemplate<Breaker = B> + 'static,
+    ) -> Result<Self> {
+        template.create()?;
+        self.overrides.insert(key.into(), Box::new(template));
+        Ok(self)
+    }
+
+    /// 获取`key`对应的熔断器, 没有时创建, 创建失败时返回模板的错误. 已经存在时只需要读锁
+    pub fn get(&self, key: &str) -> Result<Arc<B>> {
+        let now = self.now();
+        let shard = &self.shards[shard_index(key)];
+        if let Some(entry) = shard.read().entries.get(key) {
+            entry.last_used.store(now, Ordering::Relaxed);
+            return Ok(entry.breaker.clone());
+        }
+
+        let mut shard = shard.write();
+        self.sweep(&mut shard, now);
+        let entry = match shard.entries.entry(key.to_string()) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                let template = self.overrides.get(key).unwrap_or(&self.template);
+                entry.insert(Entry {
+                    breaker: Arc::new(template.create()?),
+                    last_used: AtomicU64::new(now),
+                })
+            }
+        };
+        entry.last_used.store(now, Ordering::Relaxed);
+        Ok(entry.breaker.clone())
+    }
+
+    /// 删除`key`对应的熔断器, 下次用到时重新创建
+    pub fn remove(&self, key: &str) -> Option<Arc<B>> {
+        self.shards[shard_index(key)]
+            .write()
+            .entries
+            .remove(key)
+            .map(|entry| entry.breaker)
+    }
+
+    /// 当前的 key 数量, 包含已经过期还没清理的
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 立即清理所有过期的 key. 平时创建新 key 时也会顺便清理所在的分片, 一般不需要手动调用
+    pub fn evict_idle(&self) {
+        let now = self.now();
+        for shard in &self.shards {
+            let mut shard = shard.write();
+            shard.last_sweep = 0;
+            self.sweep(&mut shard, now);
+        }
+    }
+
+    /// 清理过期的 key, 同一个分片在`idle_timeout`的一半内最多清理一次
+    fn sweep(&self, shard: &mut Shard<B>, now: u64) {
+        let idle = self.idle_timeout.as_millis() as u64;
+        if shard.last_sweep != 0 && now.saturating_sub(shard.last_sweep) < idle / 2 {
+            return;
+        }
+        shard.last_sweep = now.max(1);
+        shard
+            .entries
+            .retain(|_, entry| now.saturating_sub(entry.last_used.load(Ordering::Relaxed)) < idle);
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now().duration_since(self.start).as_millis() as u64
+    }
+}
+
+fn shard_index(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::call::Breaker;
+    use crate::sre_breaker::clock::MockClock;
+    use crate::sre_breaker::three_state::{State, TripPolicy};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_group_isolation() {
+        let mut template = ThreeStateBreakerBuilder::default();
+        template.trip(TripPolicy::ConsecutiveFailures(1));
+        let mut strict = ThreeStateBreakerBuilder::default();
+        strict.trip(TripPolicy::ConsecutiveFailures(3));
+        let group = BreakerGroup::new(template, Duration::from_secs(60))
+            .unwrap()
+            .with_override("db-shard-1", strict)
+            .unwrap();
+
+        assert!(Arc::ptr_eq(
+            &group.get("a.com").unwrap(),
+            &group.get("a.com").unwrap()
+        ));
+        let _ = group.get("a.com").unwrap().call_sync(|| Err::<(), _>(()));
+        assert_eq!(group.get("a.com").unwrap().state(), State::Open);
+        assert_eq!(group.get("b.com").unwrap().state(), State::Closed);
+
+        let _ = group
+            .get("db-shard-1")
+            .unwrap()
+            .call_sync(|| Err::<(), _>(()));
+        assert_eq!(group.get("db-shard-1").unwrap().state(), State::Closed);
+        assert_eq!(group.len(), 3);
+    }
+
+    #[test]
+    fn test_group_idle_expire() {
+        let clock = MockClock::new();
+        let group = BreakerGroup::new(|| Ok(SreBreaker::default()), Duration::from_millis(50))
+            .unwrap()
+            .with_clock(clock.clone());
+        let old = group.get("old").unwrap();
+        group.get("busy").unwrap();
+        clock.advance(Duration::from_millis(30));
+        group.get("busy").unwrap();
+        clock.advance(Duration::from_millis(30));
+        group.evict_idle();
+        assert_eq!(group.len(), 1);
+        assert!(!Arc::ptr_eq(&old, &group.get("old").unwrap()));
+    }
+
+    #[test]
+    fn test_group_concurrent() {
+        let group =
+            BreakerGroup::new(SreBreakerBuilder::default(), Duration::from_secs(60)).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        let breaker = group.get(&format!("host-{}", i % 100)).unwrap();
+                        SreBreaker::mark_success(&breaker);
+                    }
+                });
+            }
+        });
+        assert_eq!(group.len(), 100);
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        let mut invalid = SreBreakerBuilder::default();
+        invalid.buckets(0);
+        assert!(BreakerGroup::new(invalid.clone(), Duration::from_secs(60)).is_err());
+        let group = BreakerGroup::new(SreBreakerBuilder::default(), Duration::from_secs(60))
+            .unwrap()
+            .with_override("a.com", invalid);
+        assert!(group.is_err());
+
+        // 闭包模板之后失败时返回错误, 成功后照常创建
+        let fail = Arc::new(AtomicBool::new(false));
+        let group = BreakerGroup::new(
+            {
+                let fail = fail.clone();
+                move || {
+                    anyhow::ensure!(!fail.load(Ordering::Relaxed), "配置中心不可用");
+                    Ok(SreBreaker::default())
+                }
+            },
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        fail.store(true, Ordering::Relaxed);
+        assert!(group.get("a.com").is_err());
+        assert!(group.is_empty());
+        fail.store(false, Ordering::Relaxed);
+        assert!(group.get("a.com").is_ok());
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+
+use super::atomic_rolling::{epoch, resolve_shards, shard_index, AtomicBucket};
+use super::clock::{system_clock, Clock};
+use super::rolling::validate_window;
+
+/// 直方图的最大值为 2^40 微秒(约 12 天), 更大的按最大值算
+const MAX_EXP: u32 = 40;
+/// 每个 2 的幂区间分成 4 个子区间, 误差不超过 25%
+const SUB_BUCKETS: u32 = 4;
+const HISTOGRAM_SIZE: usize = ((MAX_EXP - 1) * SUB_BUCKETS) as usize;
+
+/// 滑动窗口的耗时统计, 每个桶保存一个对数直方图, 可以算出窗口内的耗时分位数和慢调用数.
+/// 和`RollingPolicy`一样, 统计时不包含当前正在写入的桶
+///
+/// 计数和`AtomicRollingPolicy`一样是带时间片的`AtomicU64`, 写入不加锁, 可以分片.
+/// 直方图的每个区间单独带时间片, 过期的区间在下次写入时才重新计数
+#[derive(Debug)]
+pub struct LatencyWindow {
+    shards: Vec<Shard>,
+    size: usize,
+    bucket_duration: Duration,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+}
+
+/// 一个分片的所有桶
+#[derive(Debug)]
+#[repr(align(64))]
+struct Shard {
+    /// 每个桶的 (慢调用数, 调用数)
+    calls: Vec<AtomicBucket>,
+    /// 每个桶的直方图依次排列, 第 i 个桶是`[i * HISTOGRAM_SIZE, (i + 1) * HISTOGRAM_SIZE)`
+    histogram: Vec<AtomicBucket>,
+}
+
+impl Shard {
+    fn new(size: usize) -> Shard {
+        Shard {
+            calls: (0..size).map(|_| AtomicBucket::default()).collect(),
+            histogram: (0..size * HISTOGRAM_SIZE)
+                .map(|_| AtomicBucket::default())
+                .collect(),
+        }
+    }
+}
+
+/// 窗口内的耗时统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyStats {
+    pub total: u64,
+    /// 超过慢调用阈值的调用数
+    pub slow: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+impl LatencyWindow {
+    /// 窗口由`size`个`bucket_duration`组成, 不分片
+    pub fn new(size: usize, bucket_duration: Duration) -> Result<LatencyWindow> {
+        LatencyWindow::with_shards(size, bucket_duration, 1)
+    }
+
+    /// 分成`shards`片, 为 0 时按 CPU 核数分片, 见`AtomicRollingPolicy::with_shards`
+    pub fn with_shards(
+        size: usize,
+        bucket_duration: Duration,
+        shards: usize,
+    ) -> Result<LatencyWindow> {
+        validate_window(size, bucket_duration)?;
+        let shards = resolve_shards(shards);
+        ensure!(shards <= 1024, "分片太多, 当前为 {shards}");
+        let clock = system_clock();
+        Ok(LatencyWindow {
+            shards: (0..shards).map(|_| Shard::new(size)).collect(),
+            size,
+            bucket_duration,
+            start: clock.now(),
+            clock,
+        })
+    }
+
+    /// 换成`clock`计时, 窗口从`clock`的当前时间开始
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> LatencyWindow {
+        self.start = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 记录一次调用的耗时, `slow`表示是否算慢调用
+    pub fn record(&self, latency: Duration, slow: bool) {
+        let epoch = self.epoch();
+        let shard = &self.shards[shard_index(self.shards.len())];
+        let bucket = epoch as usize % self.size;
+        shard.calls[bucket].add(epoch, slow as u64);
+        shard.histogram[bucket * HISTOGRAM_SIZE + index(latency)].add(epoch, 1);
+    }
+
+    /// 窗口内的 (慢调用数, 总调用数)
+    pub fn slow_summary(&self) -> (u64, u64) {
+        let epoch = self.epoch();
+        self.shards
+            .iter()
+            .flat_map(|shard| &shard.calls)
+            .fold((0, 0), |(slow, total), bucket| {
+                let (s, t) = bucket.load(epoch, self.size);
+                (slow + s, total + t)
+            })
+    }
+
+    /// 窗口内耗时的`quantile`(0 ~ 1)分位数, 返回所在区间的上界. 窗口内没有调用时返回`None`
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        self.percentiles(&[quantile])[0]
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let (slow, total) = self.slow_summary();
+        let [p50, p90, p99] = self.percentiles(&[0.5, 0.9, 0.99]).try_into().unwrap();
+        LatencyStats {
+            total,
+            slow,
+            p50: p50.unwrap_or_default(),
+            p90: p90.unwrap_or_default(),
+            p99: p99.unwrap_or_default(),
+        }
+    }
+
+    fn percentiles(&self, quantiles: &[f64]) -> Vec<Option<Duration>> {
+        let epoch = self.epoch();
+        let mut histogram = [0u64; HISTOGRAM_SIZE];
+        for bucket in self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.histogram.chunks(HISTOGRAM_SIZE))
+        {
+            for (sum, cell) in histogram.iter_mut().zip(bucket) {
+                *sum += cell.load(epoch, self.size).1;
+            }
+        }
+        let total = histogram.iter().sum::<u64>();
+        quantiles
+            .iter()
+            .map(|quantile| {
+                if total == 0 {
+                    return None;
+                }
+                // 第 rank 个调用所在的区间
+                let rank = ((quantile.clamp(0f64, 1f64) * total as f64).ceil() as u64).max(1);
+                let mut seen = 0;
+                let index = histogram.iter().position(|count| {
+                    seen += count;
+                    seen >= rank
+                })?;
+                Some(Duration::from_micros(upper_bound(index)))
+            })
+            .collect()
+    }
+
+    fn epoch(&self) -> u32 {
+        epoch(self.start, self.clock.now(), self.bucket_duration)
+    }
+}
+
+/// 耗时(微秒)所在的直方图下标: 小于 4 微秒时每微秒一个区间,
+/// 之后每个 [2^n, 2^(n+1)) 分成 4 个子区间
+fn index(latency: Duration) -> usize {
+    let micros = (latency.as_micros() as u64).min((1 << MAX_EXP) - 1);
+    if micros < SUB_BUCKETS as u64 {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros();
+    let sub = (micros >> (exp - 2)) & (SUB_BUCKETS as u64 - 1);
+    ((exp - 1) * SUB_BUCKETS) as usize + sub as usize
+}
+
+/// 下标对应区间的上界(微秒)
+fn upper_bound(index: usize) -> u64 {
+    lower_bound(index + 1) - 1
+}
+
+fn lower_bound(index: usize) -> u64 {
+    let index = index as u64;
+    let sub_buckets = SUB_BUCKETS as u64;
+    if index < sub_buckets {
+        return index;
+    }
+    let exp = index / sub_buckets + 1;
+    (sub_buckets + index % sub_buckets) << (exp - 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+
+    #[test]
+    fn test_histogram_index() {
+        for micros in [0, 1, 3, 4, 7, 8, 15, 100, 1_000, 123_456, 1 << 39] {
+            let index = index(Duration::from_micros(micros));
+            assert!(lower_bound(index) <= micros && micros <= upper_bound(index));
+        }
+        assert_eq!(index(Duration::from_secs(86400 * 365)), HISTOGRAM_SIZE - 1);
+    }
+
+    #[test]
+    fn test_latency_window() {
+        let clock = MockClock::new();
+        let window = LatencyWindow::new(10, Duration::from_millis(100))
+            .unwrap()
+            .with_clock(clock.clone());
+        for ms in 1..=100 {
+            window.record(Duration::from_millis(ms), ms > 90);
+        }
+        // 当前桶不参与统计
+        assert_eq!(window.percentile(0.5), None);
+        clock.advance(Duration::from_millis(100));
+        let stats = window.stats();
+        assert_eq!((stats.slow, stats.total), (10, 100));
+        // 误差不超过 25%
+        for (p, expected) in [(stats.p50, 50), (stats.p90, 90), (stats.p99, 99)] {
+            let p = p.as_millis() as u64;
+            assert!(p >= expected && p <= expected * 5 / 4, "{p} {expected}");
+        }
+
+        // 窗口滑过之后清空
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(window.stats(), LatencyStats::default());
+    }
+
+    #[test]
+    fn test_sharded_window() {
+        let clock = MockClock::new();
+        let window = Arc::new(
+            LatencyWindow::with_shards(10, Duration::from_millis(100), 4)
+                .unwrap()
+                .with_clock(clock.clone()),
+        );
+        let handles = (0..4)
+            .map(|_| {
+                let window = window.clone();
+                std::thread::spawn(move || {
+                    for ms in 1..=100 {
+                        window.record(Duration::from_millis(ms), ms > 90);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        clock.advance(Duration::from_millis(100));
+        let stats = window.stats();
+        assert_eq!((stats.slow, stats.total), (40, 400));
+        assert!(stats.p50 >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,255 @@
+//! tower 中间件, 给任意`tower::Service`加上熔断
+
+use std::future::{ready, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tower_layer::Layer;
+use tower_service::Service;
+
+use super::call::{after_call, before_call, Breaker, CallError};
+use super::group::BreakerGroup;
+
+/// 判断一次调用的结果算成功还是失败
+pub trait Classify<T, E> {
+    fn is_success(&self, result: &Result<T, E>) -> bool;
+}
+
+/// 默认的分类: `Ok`算成功, `Err`算失败
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IsOk;
+
+impl<T, E> Classify<T, E> for IsOk {
+    fn is_success(&self, result: &Result<T, E>) -> bool {
+        result.is_ok()
+    }
+}
+
+impl<F, T, E> Classify<T, E> for F
+where
+    F: Fn(&Result<T, E>) -> bool,
+{
+    fn is_success(&self, result: &Result<T, E>) -> bool {
+        self(result)
+    }
+}
+
+/// 为每个请求选择熔断器
+pub trait SelectBreaker<Req> {
+    type Breaker: Breaker;
+
+    /// 失败时请求不会发出, 返回`CallError::Unavailable`
+    fn select(&self, req: &Req) -> anyhow::Result<Arc<Self::Breaker>>;
+}
+
+/// 所有请求共用一个熔断器
+impl<B: Breaker, Req> SelectBreaker<Req> for Arc<B> {
+    type Breaker = B;
+
+    fn select(&self, _req: &Req) -> anyhow::Result<Arc<B>> {
+        Ok(self.clone())
+    }
+}
+
+/// 用`key`从请求中取出 key (如路由, host), 每个 key 一个熔断器
+pub struct Keyed<B, K> {
+    group: Arc<BreakerGroup<B>>,
+    key: K,
+}
+
+impl<B, K: Clone> Clone for Keyed<B, K> {
+    fn clone(&self) -> Self {
+        Keyed {
+            group: self.group.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<B, K, Req> SelectBreaker<Req> for Keyed<B, K>
+where
+    B: Breaker,
+    K: Fn(&Req) -> String,
+{
+    type Breaker = B;
+
+    fn select(&self, req: &Req) -> anyhow::Result<Arc<B>> {
+        self.group.get(&(self.key)(req))
+    }
+}
+
+/// 熔断中间件, 熔断器拒绝时不调用内部服务, 直接返回`CallError::Rejected`
+#[derive(Clone)]
+pub struct BreakerLayer<S, C = IsOk> {
+    select: S,
+    classify: C,
+}
+
+impl<B: Breaker> BreakerLayer<Arc<B>> {
+    pub fn new(breaker: Arc<B>) -> Self {
+        BreakerLayer {
+            select: breaker,
+            classify: IsOk,
+        }
+    }
+}
+
+impl<B, K> BreakerLayer<Keyed<B, K>> {
+    /// 按`key`返回的 key 从`group`中取熔断器
+    pub fn keyed(group: Arc<BreakerGroup<B>>, key: K) -> Self {
+        BreakerLayer {
+            select: Keyed { group, key },
+            classify: IsOk,
+        }
+    }
+}
+
+impl<S, C> BreakerLayer<S, C> {
+    /// 用`classify`判断结果算不算成功, 比如 4xx 算成功, 5xx 算失败
+    pub fn classify<C2>(self, classify: C2) -> BreakerLayer<S, C2> {
+        BreakerLayer {
+            select: self.select,
+            classify,
+        }
+    }
+}
+
+impl<Svc, S: Clone, C: Clone> Layer<Svc> for BreakerLayer<S, C> {
+    type Service = BreakerService<Svc, S, C>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        BreakerService {
+            inner,
+            select: self.select.clone(),
+            classify: self.classify.clone(),
+        }
+    }
+}
+
+/// `BreakerLayer`包装后的服务
+#[derive(Clone)]
+pub struct BreakerService<Svc, S, C> {
+    inner: Svc,
+    select: S,
+    classify: C,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<Svc, S, C, Req> Service<Req> for BreakerService<Svc, S, C>
+where
+    Svc: Service<Req>,
+    Svc::Future: Send + 'static,
+    Svc::Response: Send + 'static,
+    Svc::Error: Send + 'static,
+    S: SelectBreaker<Req>,
+    S::Breaker: Send + Sync + 'static,
+    C: Classify<Svc::Response, Svc::Error> + Clone + Send + 'static,
+{
+    type Response = Svc::Response;
+    type Error = CallError<Svc::Error>;
+    type Future = BoxFuture<Result<Svc::Response, CallError<Svc::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(CallError::Inner)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let breaker = match self.select.select(&req) {
+            Ok(breaker) => breaker,
+            Err(err) => return Box::pin(ready(Err(CallError::Unavailable(err)))),
+        };
+        let start = match before_call(&*breaker) {
+            Ok(start) => start,
+            Err(err) => return Box::pin(ready(Err(err))),
+        };
+        let fut = self.inner.call(req);
+        let classify = self.classify.clone();
+        Box::pin(async move {
+            let result = fut.await;
+            after_call(&*breaker, start, result, |result| {
+                classify.is_success(result)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::three_state::{
+        State, ThreeStateBreaker, ThreeStateBreakerBuilder, TripPolicy,
+    };
+    use std::convert::Infallible;
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    /// 请求是 (路由, 状态码), 原样返回状态码
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<(&'static str, u16)> for Echo {
+        type Response = u16;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<u16, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: (&'static str, u16)) -> Self::Future {
+            ready(Ok(req.1))
+        }
+    }
+
+    fn template() -> ThreeStateBreakerBuilder {
+        let mut builder = ThreeStateBreakerBuilder::default();
+        builder.trip(TripPolicy::ConsecutiveFailures(2));
+        builder
+    }
+
+    async fn send<Svc>(svc: &mut Svc, req: (&'static str, u16)) -> Result<u16, Svc::Error>
+    where
+        Svc: Service<(&'static str, u16), Response = u16>,
+    {
+        poll_fn(|cx| svc.poll_ready(cx)).await?;
+        svc.call(req).await
+    }
+
+    fn server_error(result: &Result<u16, Infallible>) -> bool {
+        !matches!(result, Ok(500..))
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let breaker = Arc::new(template().build().unwrap());
+        let mut svc = BreakerLayer::new(breaker.clone())
+            .classify(server_error)
+            .layer(Echo);
+        assert_eq!(send(&mut svc, ("/", 404)).await.unwrap(), 404);
+        assert_eq!(breaker.state(), State::Closed);
+        for _ in 0..2 {
+            assert_eq!(send(&mut svc, ("/", 500)).await.unwrap(), 500);
+        }
+        assert_eq!(breaker.state(), State::Open);
+        assert!(send(&mut svc, ("/", 200)).await.unwrap_err().is_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_keyed_layer() {
+        let group = Arc::new(
+            BreakerGroup::<ThreeStateBreaker>::new(template(), Duration::from_secs(60)).unwrap(),
+        );
+        let mut svc = BreakerLayer::keyed(group.clone(), |req: &(&str, u16)| req.0.to_string())
+            .classify(server_error)
+            .layer(Echo);
+        for _ in 0..2 {
+            let _ = send(&mut svc, ("/a", 500)).await;
+        }
+        assert!(send(&mut svc, ("/a", 200)).await.unwrap_err().is_rejected());
+        // 其他路由不受影响
+        assert_eq!(send(&mut svc, ("/b", 200)).await.unwrap(), 200);
+        assert_eq!(group.len(), 2);
+    }
+}
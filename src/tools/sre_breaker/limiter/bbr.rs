@@ -0,0 +1,427 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::cpu::{CpuSampler, ProcStatSampler};
+use super::LimitError;
+use crate::sre_breaker::clock::{system_clock, Clock};
+use crate::sre_breaker::rolling::RollingPolicy;
+
+/// CPU 使用率的采样间隔
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// 丢弃请求后, 即使 CPU 降下来也继续按并发数判断的时间, 避免抖动
+const COOL_DOWN: Duration = Duration::from_secs(1);
+
+#[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(private, name = "build_inner", validate = "Self::validate"))]
+/// 服务端自适应限流, 参考 kratos 的 bbr 算法
+///
+/// CPU 使用率超过`cpu_threshold`时, 用窗口内每个桶的最大通过数和最小耗时估算系统能承受的并发数,
+/// 正在处理的请求超过这个数就丢弃新请求. 丢弃之后的 1 秒内即使 CPU 降下来也继续按并发数判断.
+/// CPU 使用率由进程内共享的后台线程每 500 毫秒采样一次, 线程在第一次创建`Bbr`时启动,
+/// 所有`Bbr`共用. `allow`只读原子变量, `Bbr`释放后采样线程不再为它采样
+pub struct Bbr {
+    /// 统计窗口
+    #[builder(default = "Duration::from_secs(10)")]
+    window: Duration,
+    /// 窗口分成多少个桶
+    #[builder(default = "100")]
+    buckets: usize,
+    /// CPU 使用率阈值, 千分比
+    #[builder(default = "800")]
+    cpu_threshold: u64,
+    /// CPU 使用率的滑动平均系数(0 ~ 1), 越大越平滑, 为 0 时直接使用采样值. 默认和 kratos 一致
+    #[builder(default = "0.95")]
+    cpu_decay: f64,
+    /// CPU 使用率采样, 默认读`/proc/stat`, 使用默认采样的`Bbr`共用一个`ProcStatSampler`
+    #[builder(default = "default_sampler()")]
+    cpu_sampler: Arc<dyn CpuSampler>,
+    /// 时钟, 默认是系统时钟
+    #[builder(default = "system_clock()")]
+    clock: Arc<dyn Clock>,
+
+    /// 每个桶通过的请求数
+    #[builder(setter(skip), default = "self.rolling_policy()?")]
+    pass_stat: RollingPolicy,
+    /// 每个桶请求的耗时(毫秒)
+    #[builder(setter(skip), default = "self.rolling_policy()?")]
+    rt_stat: RollingPolicy,
+    #[builder(setter(skip), default)]
+    in_flight: AtomicI64,
+    /// 上次丢弃请求的时间, 距离`start`的纳秒数, 0 表示冷却已经结束
+    #[builder(setter(skip), default)]
+    prev_drop: AtomicU64,
+    /// 采样线程更新的 CPU 使用率
+    #[builder(setter(skip), default)]
+    cpu: Arc<CpuStat>,
+    #[builder(
+        setter(skip),
+        default = "self.clock.clone().unwrap_or_else(system_clock).now()"
+    )]
+    start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CpuStat {
+    /// 滑动平均后的 CPU 使用率(千分比), 存的是 f64 的二进制表示
+    usage: AtomicU64,
+    /// 是否已经采样过, 第一次采样直接使用采样值
+    sampled: AtomicBool,
+}
+
+impl CpuStat {
+    fn usage(&self) -> u64 {
+        f64::from_bits(self.usage.load(Ordering::Relaxed)) as u64
+    }
+
+    /// 记录一次采样值并做滑动平均, 只有采样线程会调用
+    fn sample(&self, sample: u64, decay: f64) {
+        let sample = sample.min(1000) as f64;
+        let usage = if self.sampled.swap(true, Ordering::Relaxed) {
+            let prev = f64::from_bits(self.usage.load(Ordering::Relaxed));
+            prev * decay + sample * (1f64 - decay)
+        } else {
+            sample
+        };
+        self.usage.store(usage.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// 注册到采样线程的`Bbr`
+struct Registration {
+    stat: Weak<CpuStat>,
+    sampler: Arc<dyn CpuSampler>,
+    decay: f64,
+}
+
+type Registrations = Arc<Mutex<Vec<Registration>>>;
+
+/// 进程内共享的采样线程, 启动失败时保存错误
+static SAMPLING: OnceLock<Result<Registrations, String>> = OnceLock::new();
+
+/// 默认的`/proc/stat`采样, 所有`Bbr`共用, 每次采样只读一次
+fn default_sampler() -> Arc<dyn CpuSampler> {
+    static SAMPLER: OnceLock<Arc<ProcStatSampler>> = OnceLock::new();
+    SAMPLER.get_or_init(Default::default).clone()
+}
+
+/// 启动采样线程, 每`CPU_SAMPLE_INTERVAL`为所有存活的`Bbr`采样一次, 线程不会退出
+fn start_sampling() -> Result<Registrations, String> {
+    let registrations = Registrations::default();
+    let shared = registrations.clone();
+    std::thread::Builder::new()
+        .name("bbr-cpu".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(CPU_SAMPLE_INTERVAL);
+            sample_all(&shared);
+        })
+        .map_err(|e| format!("启动 CPU 采样线程失败: {e}"))?;
+    Ok(registrations)
+}
+
+/// 清理已经释放的`Bbr`, 同一个`CpuSampler`只采样一次, 结果给所有用它的`Bbr`
+fn sample_all(registrations: &Mutex<Vec<Registration>>) {
+    let alive = {
+        let mut registrations = registrations.lock();
+        registrations.retain(|registration| registration.stat.strong_count() > 0);
+        registrations
+            .iter()
+            .map(|r| (r.stat.clone(), r.sampler.clone(), r.decay))
+            .collect::<Vec<_>>()
+    };
+    let mut samples: Vec<(Arc<dyn CpuSampler>, u64)> = Vec::new();
+    for (stat, sampler, decay) in alive {
+        let Some(stat) = stat.upgrade() else {
+            continue;
+        };
+        let sample = match samples.iter().find(|(s, _)| Arc::ptr_eq(s, &sampler)) {
+            Some(&(_, sample)) => sample,
+            None => {
+                let sample = sampler.usage();
+                samples.push((sampler, sample));
+                sample
+            }
+        };
+        stat.sample(sample, decay);
+    }
+}
+
+/// 限流器的统计快照
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BbrStats {
+    /// 滑动平均后的 CPU 使用率, 千分比
+    pub cpu: u64,
+    pub in_flight: i64,
+    /// 估算的最大并发数
+    pub max_in_flight: i64,
+    /// 单个桶的最大通过数
+    pub max_pass: u64,
+    /// 单个桶的最小平均耗时, 毫秒
+    pub min_rt: u64,
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        BbrBuilder::default().build().unwrap()
+    }
+}
+
+impl BbrBuilder {
+    /// 创建限流器并注册到 CPU 采样线程, 第一次调用时启动线程
+    pub fn build(&self) -> Result<Bbr, BbrBuilderError> {
+        let registrations = SAMPLING
+            .get_or_init(start_sampling)
+            .as_ref()
+            .map_err(Clone::clone)?;
+        let bbr = self.build_inner()?;
+        registrations.lock().push(Registration {
+            stat: Arc::downgrade(&bbr.cpu),
+            sampler: bbr.cpu_sampler.clone(),
+            decay: bbr.cpu_decay,
+        });
+        Ok(bbr)
+    }
+
+    fn rolling_policy(&self) -> Result<RollingPolicy, String> {
+        let (window, buckets) = self.window_buckets();
+        let policy = RollingPolicy::new(buckets, window / buckets.max(1) as u32)
+            .map_err(|e| e.to_string())?;
+        Ok(policy.with_clock(self.clock.clone().unwrap_or_else(system_clock)))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0f64..1f64).contains(&self.cpu_decay.unwrap_or(0f64)) {
+            return Err("CPU 使用率的滑动平均系数必须在 0 ~ 1 之间".to_string());
+        }
+        let (window, buckets) = self.window_buckets();
+        crate::sre_breaker::rolling::validate_window(buckets, window / buckets.max(1) as u32)
+            .map_err(|e| e.to_string())
+    }
+
+    fn window_buckets(&self) -> (Duration, usize) {
+        (
+            self.window.unwrap_or(Duration::from_secs(10)),
+            self.buckets.unwrap_or(100),
+        )
+    }
+}
+
+impl Bbr {
+    /// 是否放行请求, 放行时返回的`BbrGuard`要在请求处理完后释放, 用来统计并发数和耗时
+    pub fn allow(&self) -> Result<BbrGuard<'_>, LimitError> {
+        if self.should_drop() {
+            return Err(LimitError::Overloaded);
+        }
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok(BbrGuard {
+            bbr: self,
+            start: self.clock.now(),
+        })
+    }
+
+    pub fn stats(&self) -> BbrStats {
+        BbrStats {
+            cpu: self.cpu.usage(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            max_in_flight: self.max_in_flight(),
+            max_pass: self.max_pass(),
+            min_rt: self.min_rt(),
+        }
+    }
+
+    fn should_drop(&self) -> bool {
+        let now = self.elapsed_nanos();
+        if self.cpu.usage() < self.cpu_threshold {
+            let prev_drop = self.prev_drop.load(Ordering::Relaxed);
+            if prev_drop == 0 {
+                return false;
+            }
+            if now.saturating_sub(prev_drop) <= COOL_DOWN.as_nanos() as u64 {
+                return self.overloaded();
+            }
+            let _ =
+                self.prev_drop
+                    .compare_exchange(prev_drop, 0, Ordering::Relaxed, Ordering::Relaxed);
+            return false;
+        }
+        let drop = self.overloaded();
+        if drop {
+            // 只记录冷却开始的时间
+            let _ = self.prev_drop.compare_exchange(
+                0,
+                now.max(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        drop
+    }
+
+    fn overloaded(&self) -> bool {
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        in_flight > 1 && in_flight > self.max_in_flight()
+    }
+
+    /// 单个桶的最大通过数乘以每秒桶数, 得到每秒最大通过数, 再乘以最小耗时(秒), 就是能承受的并发数
+    fn max_in_flight(&self) -> i64 {
+        let buckets_per_second = self.buckets as f64 / self.window.as_secs_f64();
+        let max = self.max_pass() as f64 * buckets_per_second * self.min_rt() as f64 / 1000f64;
+        (max + 0.5).floor() as i64
+    }
+
+    fn max_pass(&self) -> u64 {
+        self.pass_stat
+            .fold_buckets(0, |max, (sum, _)| max.max(sum))
+            .max(1)
+    }
+
+    fn min_rt(&self) -> u64 {
+        self.rt_stat
+            .fold_buckets(None, |min: Option<u64>, (sum, total)| {
+                if total == 0 {
+                    return min;
+                }
+                let rt = sum.div_ceil(total);
+                Some(min.map_or(rt, |min| min.min(rt)))
+            })
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    fn elapsed_nanos(&self) -> u64 {
+        self.clock.now().duration_since(self.start).as_nanos() as u64
+    }
+}
+
+/// 正在处理的请求, 释放时记录耗时和通过数
+#[derive(Debug)]
+pub struct BbrGuard<'a> {
+    bbr: &'a Bbr,
+    start: Instant,
+}
+
+impl Drop for BbrGuard<'_> {
+    fn drop(&mut self) {
+        let rt = self.bbr.clock.now().duration_since(self.start);
+        // 向上取整到毫秒, 至少 1 毫秒
+        let rt_ms = (rt.as_nanos().div_ceil(1_000_000) as u64).max(1);
+        self.bbr.rt_stat.add(rt_ms);
+        self.bbr.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.bbr.pass_stat.add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+    use std::thread;
+
+    #[derive(Debug, Default)]
+    struct MockCpu(AtomicU64);
+
+    impl CpuSampler for MockCpu {
+        fn usage(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    /// 不等后台线程, 直接采样一次
+    fn sample(bbr: &Bbr) {
+        bbr.cpu.sample(bbr.cpu_sampler.usage(), bbr.cpu_decay);
+    }
+
+    fn bbr() -> (Bbr, Arc<MockClock>, Arc<MockCpu>) {
+        let clock = MockClock::new();
+        let cpu = Arc::new(MockCpu::default());
+        let bbr = BbrBuilder::default()
+            .cpu_decay(0.0)
+            .clock(clock.clone())
+            .cpu_sampler(cpu.clone())
+            .build()
+            .unwrap();
+        // 每个桶通过 20 个, 每个耗时 50ms
+        for _ in 0..3 {
+            let guards = (0..20).map(|_| bbr.allow().unwrap()).collect::<Vec<_>>();
+            clock.advance(Duration::from_millis(50));
+            drop(guards);
+            clock.advance(Duration::from_millis(50));
+        }
+        (bbr, clock, cpu)
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let (bbr, _, _) = bbr();
+        let stats = bbr.stats();
+        assert_eq!((stats.max_pass, stats.min_rt), (20, 50));
+        // 20 * 每秒 10 个桶 * 0.05 秒
+        assert_eq!(stats.max_in_flight, 10);
+    }
+
+    #[test]
+    fn test_drop_when_cpu_high() {
+        let (bbr, clock, cpu) = bbr();
+        // CPU 不高时不限制并发
+        let mut guards = (0..12).map(|_| bbr.allow().unwrap()).collect::<Vec<_>>();
+
+        cpu.0.store(1000, Ordering::Relaxed);
+        clock.advance(CPU_SAMPLE_INTERVAL);
+        sample(&bbr);
+        assert_eq!(bbr.allow().unwrap_err(), LimitError::Overloaded);
+        guards.truncate(10);
+        guards.push(bbr.allow().unwrap());
+        assert!(bbr.allow().is_err());
+        assert_eq!(bbr.stats().max_in_flight, 10);
+
+        // CPU 降下来后, 冷却期内还按并发数判断
+        cpu.0.store(0, Ordering::Relaxed);
+        clock.advance(CPU_SAMPLE_INTERVAL);
+        sample(&bbr);
+        assert_eq!(bbr.stats().cpu, 0);
+        assert!(bbr.allow().is_err());
+        clock.advance(COOL_DOWN - CPU_SAMPLE_INTERVAL + Duration::from_millis(1));
+        assert!(bbr.allow().is_ok());
+        assert!(BbrBuilder::default().cpu_decay(1.0).build().is_err());
+    }
+
+    #[test]
+    fn test_background_sampling() {
+        let cpu = Arc::new(MockCpu(AtomicU64::new(900)));
+        let bbr = BbrBuilder::default()
+            .cpu_decay(0.5)
+            .cpu_sampler(cpu.clone())
+            .build()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        // 第一次采样直接使用采样值, 不是从 0 开始平均
+        while bbr.stats().cpu != 900 {
+            assert!(Instant::now() < deadline, "后台线程没有采样");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // 释放后采样线程不再持有 sampler
+        drop(bbr);
+        while Arc::strong_count(&cpu) > 1 {
+            assert!(Instant::now() < deadline, "采样线程没有释放 sampler");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shared_sampling_thread() {
+        let limiters = (0..10).map(|_| Bbr::default()).collect::<Vec<_>>();
+        let threads = std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .filter(|name| name.trim() == "bbr-cpu")
+            .count();
+        assert_eq!(threads, 1);
+        assert!(Arc::ptr_eq(
+            &limiters[0].cpu_sampler,
+            &limiters[9].cpu_sampler
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt::Debug;
+
+use parking_lot::Mutex;
+
+/// CPU 使用率采样, 返回千分比(0 ~ 1000). 测试时可以换成固定值
+pub trait CpuSampler: Debug + Send + Sync {
+    fn usage(&self) -> u64;
+}
+
+/// 读`/proc/stat`计算整机的 CPU 使用率, 每次返回距离上次采样这段时间内的使用率.
+/// 创建时先读一次作为基准, 否则第一次采样得到的是开机以来的平均值.
+/// 非 Linux 系统上总是返回 0, 即不会因为 CPU 触发限流
+#[derive(Debug)]
+pub struct ProcStatSampler {
+    /// 上次采样的 (空闲时间, 总时间)
+    last: Mutex<Option<(u64, u64)>>,
+}
+
+impl Default for ProcStatSampler {
+    fn default() -> Self {
+        ProcStatSampler {
+            last: Mutex::new(read_proc_stat()),
+        }
+    }
+}
+
+impl CpuSampler for ProcStatSampler {
+    fn usage(&self) -> u64 {
+        let Some((idle, total)) = read_proc_stat() else {
+            return 0;
+        };
+        let mut last = self.last.lock();
+        // 没有基准时这次只记录, 不计算
+        let Some((last_idle, last_total)) = last.replace((idle, total)) else {
+            return 0;
+        };
+        let total = total.saturating_sub(last_total);
+        if total == 0 {
+            return 0;
+        }
+        let busy = total.saturating_sub(idle.saturating_sub(last_idle));
+        busy * 1000 / total
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc_stat() -> Option<(u64, u64)> {
+    parse_proc_stat(&std::fs::read_to_string("/proc/stat").ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_proc_stat() -> Option<(u64, u64)> {
+    None
+}
+
+/// 解析第一行`cpu  user nice system idle iowait irq softirq steal guest guest_nice`,
+/// guest 已经算在 user 里, 不重复计算. 返回 (idle + iowait, 总时间)
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_stat(content: &str) -> Option<(u64, u64)> {
+    let line = content.lines().next()?;
+    let mut fields = line.split_whitespace();
+    if fields.next()? != "cpu" {
+        return None;
+    }
+    let values = fields
+        .take(8)
+        .map(|v| v.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if values.len() < 4 {
+        return None;
+    }
+    let idle = values[3] + values.get(4).copied().unwrap_or(0);
+    Some((idle, values.iter().sum()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let content = "cpu  100 0 50 800 50 0 0 0 20 0\ncpu0 100 0 50 800 50 0 0 0 20 0\n";
+        assert_eq!(parse_proc_stat(content), Some((850, 1000)));
+        assert_eq!(parse_proc_stat("intr 1 2 3"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_proc_stat_sampler() {
+        let sampler = ProcStatSampler::default();
+        assert!(sampler.last.lock().is_some());
+        assert!(sampler.usage() <= 1000);
+        assert!(sampler.usage() <= 1000);
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::{LimitError, Limiter};
+use crate::sre_breaker::group::{BreakerGroup, BreakerTemplate};
+
+/// 按 key (如用户, 客户端 IP) 分别限流, 每个 key 第一次用到时按模板创建限流器,
+/// 超过`idle_timeout`没用过的 key 会被清理
+pub struct KeyedLimiter<L> {
+    group: BreakerGroup<L>,
+}
+
+impl<L: Limiter> KeyedLimiter<L> {
+    /// `template`一般是创建限流器的闭包, 如`|| TokenBucket::new(10, 5.0)`,
+    /// 会先创建一次, 参数有误时返回错误
+    pub fn new(
+        template: impl BreakerTemplate<Breaker = L> + 'static,
+        idle_timeout: Duration,
+    ) -> Result<Self> {
+        Ok(KeyedLimiter {
+            group: BreakerGroup::new(template, idle_timeout)?,
+        })
+    }
+
+    /// 给`key`单独配置, 如 VIP 用户放宽限制
+    pub fn with_override(
+        self,
+        key: impl Into<String>,
+        template: impl BreakerTemplate<Breaker = L> + 'static,
+    ) -> Result<Self> {
+        Ok(KeyedLimiter {
+            group: self.group.with_override(key, template)?,
+        })
+    }
+
+    /// `key`对应的限流器, 没有时按模板创建, 创建失败时返回模板的错误
+    pub fn get(&self, key: &str) -> Result<Arc<L>> {
+        self.group.get(key)
+    }
+
+    /// 限流器创建失败时返回`LimitError::Unavailable`
+    pub fn try_acquire(&self, key: &str, n: u64) -> Result<(), LimitError> {
+        self.limiter(key)?.try_acquire(n)
+    }
+
+    #[cfg(feature = "breaker_async")]
+    pub async fn acquire(&self, key: &str, n: u64) -> Result<(), LimitError> {
+        self.limiter(key)?.acquire(n).await
+    }
+
+    fn limiter(&self, key: &str) -> Result<Arc<L>, LimitError> {
+        self.get(key)
+            .map_err(|err| LimitError::Unavailable(format!("{err:#}")))
+    }
+
+    /// 当前的 key 数量
+    pub fn len(&self) -> usize {
+        self.group.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.group.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+    use crate::sre_breaker::limiter::TokenBucket;
+
+    #[test]
+    fn test_keyed_limiter() {
+        let clock = MockClock::new();
+        let limiter = KeyedLimiter::new(
+            {
+                let clock = clock.clone();
+                move || Ok(TokenBucket::new(2, 1.0)?.with_clock(clock.clone()))
+            },
+            Duration::from_secs(60),
+        )
+        .unwrap()
+        .with_override("vip", || TokenBucket::new(100, 1.0))
+        .unwrap();
+
+        assert!(limiter.try_acquire("alice", 2).is_ok());
+        assert!(limiter.try_acquire("alice", 1).is_err());
+        // 不同用户互不影响
+        assert!(limiter.try_acquire("bob", 2).is_ok());
+        assert!(limiter.try_acquire("vip", 50).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.try_acquire("alice", 1).is_ok());
+        assert_eq!(limiter.len(), 3);
+
+        assert!(KeyedLimiter::new(|| TokenBucket::new(0, 1.0), Duration::from_secs(60)).is_err());
+    }
+}
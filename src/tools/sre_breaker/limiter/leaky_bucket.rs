@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, ensure, Result};
+use parking_lot::Mutex;
+
+use super::{check_capacity, LimitError, Limiter};
+use crate::sre_breaker::clock::{system_clock, Clock};
+
+/// 排满要等的最长时间, 约 136 年, 再长计算放行的时间点时可能溢出
+const MAX_QUEUE: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// 漏桶: 请求按`rate`每秒匀速放行, 不允许突发. `acquire`会排队等到自己的时间点,
+/// 排队中的许可最多`capacity`个, 排满时继续等待
+#[derive(Debug)]
+pub struct LeakyBucket {
+    capacity: u64,
+    /// 每个许可间隔多久
+    interval: Duration,
+    /// 排满`capacity`个许可要等多久
+    queue: Duration,
+    /// 下一个许可可以放行的时间
+    next: Mutex<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl LeakyBucket {
+    /// 每秒放行`rate`个许可, 最多排队`capacity`个. 间隔算出来是 0, 或者排满要等太久时返回错误
+    pub fn new(capacity: u64, rate: f64) -> Result<LeakyBucket> {
+        ensure!(capacity > 0, "漏桶的容量不能为 0");
+        ensure!(rate > 0f64 && rate.is_finite(), "漏桶的速率必须大于 0");
+        let interval = Duration::try_from_secs_f64(1f64 / rate)
+            .map_err(|_| anyhow!("漏桶的速率太小, 当前为 {rate}"))?;
+        ensure!(!interval.is_zero(), "漏桶的速率太大, 当前为 {rate}");
+        let queue = u32::try_from(capacity)
+            .ok()
+            .and_then(|capacity| interval.checked_mul(capacity))
+            .filter(|queue| *queue <= MAX_QUEUE)
+            .ok_or_else(|| anyhow!("漏桶排满要等的时间超出范围, 容量 {capacity}, 速率 {rate}"))?;
+        let clock = system_clock();
+        Ok(LeakyBucket {
+            capacity,
+            interval,
+            queue,
+            next: Mutex::new(clock.now()),
+            clock,
+        })
+    }
+
+    /// 换成`clock`计时
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> LeakyBucket {
+        *self.next.get_mut() = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 排到队里, 返回需要等多久, 不用 tokio 时可以自己 sleep 这么久再放行.
+    /// 队列满时不排队, 返回`LimitError::Limited`和队列腾出位置要等的时间
+    pub fn reserve(&self, n: u64) -> Result<Duration, LimitError> {
+        check_capacity(n, self.capacity)?;
+        let now = self.clock.now();
+        let mut next = self.next.lock();
+        let start = (*next).max(now);
+        let wait = start - now;
+        let span = self.span(n);
+        if wait + span > self.queue {
+            return Err(LimitError::Limited(wait + span - self.queue));
+        }
+        *next = start + span;
+        Ok(wait)
+    }
+
+    /// `n`个许可要占多久, 调用前要用`check_capacity`保证`n`不超过容量, 这时不会溢出
+    fn span(&self, n: u64) -> Duration {
+        self.interval * n as u32
+    }
+}
+
+impl Limiter for LeakyBucket {
+    fn try_acquire(&self, n: u64) -> Result<(), LimitError> {
+        check_capacity(n, self.capacity)?;
+        let now = self.clock.now();
+        let mut next = self.next.lock();
+        if *next > now {
+            return Err(LimitError::Limited(*next - now));
+        }
+        *next = now + self.span(n);
+        Ok(())
+    }
+
+    #[cfg(feature = "breaker_async")]
+    async fn acquire(&self, n: u64) -> Result<(), LimitError> {
+        loop {
+            match self.reserve(n) {
+                Ok(wait) => {
+                    if !wait.is_zero() {
+                        tokio::time::sleep(wait).await;
+                    }
+                    return Ok(());
+                }
+                Err(LimitError::Limited(wait)) => tokio::time::sleep(wait).await,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+
+    #[test]
+    fn test_leaky_bucket() {
+        let clock = MockClock::new();
+        let bucket = LeakyBucket::new(5, 10.0).unwrap().with_clock(clock.clone());
+        assert!(bucket.try_acquire(2).is_ok());
+        // 不允许突发, 要等 2 个许可的时间
+        assert_eq!(
+            bucket.try_acquire(1),
+            Err(LimitError::Limited(Duration::from_millis(200)))
+        );
+        clock.advance(Duration::from_millis(200));
+        assert!(bucket.try_acquire(1).is_ok());
+
+        // 排队: 前一个许可 100ms 后放行, 队列还能排 4 个
+        assert_eq!(bucket.reserve(3), Ok(Duration::from_millis(100)));
+        assert_eq!(
+            bucket.reserve(2),
+            Err(LimitError::Limited(Duration::from_millis(100)))
+        );
+        assert!(matches!(
+            bucket.try_acquire(6),
+            Err(LimitError::ExceedsCapacity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert!(LeakyBucket::new(10, 1e-300).is_err());
+        assert!(LeakyBucket::new(10, 1e30).is_err());
+        assert!(LeakyBucket::new(u32::MAX as u64 + 1, 1e6).is_err());
+        assert!(LeakyBucket::new(u32::MAX as u64, 0.5).is_err());
+        let bucket = LeakyBucket::new(u32::MAX as u64, 1e3).unwrap();
+        assert!(bucket.try_acquire(u32::MAX as u64).is_ok());
+        assert!(matches!(
+            bucket.try_acquire(u64::MAX),
+            Err(LimitError::ExceedsCapacity { .. })
+        ));
+    }
+
+    #[cfg(feature = "breaker_async")]
+    #[tokio::test]
+    async fn test_acquire_smooth() {
+        let bucket = LeakyBucket::new(10, 100.0).unwrap();
+        let start = Instant::now();
+        for _ in 0..5 {
+            bucket.acquire(1).await.unwrap();
+        }
+        // 第一个立即放行, 之后每 10ms 一个
+        assert!(start.elapsed() >= Duration::from_millis(35));
+    }
+}
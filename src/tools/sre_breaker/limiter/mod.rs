@@ -0,0 +1,61 @@
+//! 限流器: 令牌桶, 漏桶, 滑动窗口, 按 key 限流, 以及服务端的自适应限流 bbr
+
+use std::time::Duration;
+
+mod bbr;
+mod cpu;
+mod keyed;
+mod leaky_bucket;
+mod sliding_window;
+mod token_bucket;
+
+pub use bbr::{Bbr, BbrBuilder, BbrGuard, BbrStats};
+pub use cpu::{CpuSampler, ProcStatSampler};
+pub use keyed::KeyedLimiter;
+pub use leaky_bucket::LeakyBucket;
+pub use sliding_window::SlidingWindowLimiter;
+pub use token_bucket::TokenBucket;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitError {
+    /// 许可不够, 至少要等`0`之后再试
+    #[error("ratelimit: too many requests, retry after {0:?}")]
+    Limited(Duration),
+    /// 一次要的许可比容量还大, 永远拿不到
+    #[error("ratelimit: acquire {requested} permits exceeds capacity {capacity}")]
+    ExceedsCapacity { requested: u64, capacity: u64 },
+    /// 服务过载, 请求被丢弃
+    #[error("ratelimit: overloaded")]
+    Overloaded,
+    /// 没有可用的限流器, 比如`KeyedLimiter`按模板创建失败, 内容是创建时的错误
+    #[error("ratelimit: limiter unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// 限流器
+#[cfg_attr(feature = "breaker_async", allow(async_fn_in_trait))]
+pub trait Limiter {
+    /// 立即获取`n`个许可, 不够时返回`LimitError::Limited`, 不会等待
+    fn try_acquire(&self, n: u64) -> Result<(), LimitError>;
+
+    /// 获取`n`个许可, 不够时等待, 只有许可超过容量时返回错误. 需要`breaker_async`
+    #[cfg(feature = "breaker_async")]
+    async fn acquire(&self, n: u64) -> Result<(), LimitError> {
+        loop {
+            match self.try_acquire(n) {
+                Err(LimitError::Limited(wait)) => tokio::time::sleep(wait).await,
+                result => return result,
+            }
+        }
+    }
+}
+
+fn check_capacity(requested: u64, capacity: u64) -> Result<(), LimitError> {
+    if requested > capacity {
+        return Err(LimitError::ExceedsCapacity {
+            requested,
+            capacity,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use parking_lot::Mutex;
+
+use super::{check_capacity, LimitError, Limiter};
+use crate::sre_breaker::clock::Clock;
+use crate::sre_breaker::rolling::RollingPolicy;
+
+/// 滑动窗口限流: 最近`buckets * bucket_duration`内最多放行`limit`个许可.
+/// 窗口按桶滑动, 桶越多越平滑
+#[derive(Debug)]
+pub struct SlidingWindowLimiter {
+    limit: u64,
+    policy: RollingPolicy,
+    /// 检查和计数要一起做, 否则并发时会超出`limit`
+    lock: Mutex<()>,
+}
+
+impl SlidingWindowLimiter {
+    pub fn new(
+        limit: u64,
+        buckets: usize,
+        bucket_duration: Duration,
+    ) -> Result<SlidingWindowLimiter> {
+        ensure!(limit > 0, "限流的许可数不能为 0");
+        Ok(SlidingWindowLimiter {
+            limit,
+            policy: RollingPolicy::new(buckets, bucket_duration)?,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// 换成`clock`计时
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SlidingWindowLimiter {
+        self.policy = self.policy.with_clock(clock);
+        self
+    }
+
+    /// 当前窗口内已经放行的许可数
+    pub fn used(&self) -> u64 {
+        self.policy.reduce_with_current().0
+    }
+}
+
+impl Limiter for SlidingWindowLimiter {
+    fn try_acquire(&self, n: u64) -> Result<(), LimitError> {
+        check_capacity(n, self.limit)?;
+        let _guard = self.lock.lock();
+        if self.used() + n > self.limit {
+            // 窗口滑动后才可能有许可过期
+            return Err(LimitError::Limited(self.policy.until_next_bucket()));
+        }
+        self.policy.add(n);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+
+    #[test]
+    fn test_sliding_window() {
+        let clock = MockClock::new();
+        let limiter = SlidingWindowLimiter::new(10, 4, Duration::from_millis(250))
+            .unwrap()
+            .with_clock(clock.clone());
+        assert!(limiter.try_acquire(6).is_ok());
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.try_acquire(4).is_ok());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(
+            limiter.try_acquire(1),
+            Err(LimitError::Limited(Duration::from_millis(150)))
+        );
+        // 第一批许可所在的桶滑出窗口
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(limiter.used(), 4);
+        assert!(limiter.try_acquire(6).is_ok());
+        assert!(limiter.try_acquire(1).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use parking_lot::Mutex;
+
+use super::{check_capacity, LimitError, Limiter};
+use crate::sre_breaker::clock::{system_clock, Clock};
+
+/// 令牌桶: 按`rate`每秒匀速放入令牌, 最多攒`capacity`个, 允许一次性用掉攒下的令牌
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: u64,
+    rate: f64,
+    state: Mutex<State>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct State {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// 每秒放入`rate`个令牌, 最多攒`capacity`个, 一开始是满的
+    pub fn new(capacity: u64, rate: f64) -> Result<TokenBucket> {
+        ensure!(capacity > 0, "令牌桶的容量不能为 0");
+        ensure!(rate > 0f64 && rate.is_finite(), "令牌桶的速率必须大于 0");
+        ensure!(
+            Duration::try_from_secs_f64(capacity as f64 / rate).is_ok(),
+            "令牌桶攒满要等的时间超出范围, 容量 {capacity}, 速率 {rate}"
+        );
+        let clock = system_clock();
+        Ok(TokenBucket {
+            capacity,
+            rate,
+            state: Mutex::new(State {
+                tokens: capacity as f64,
+                last_refill: clock.now(),
+            }),
+            clock,
+        })
+    }
+
+    /// 换成`clock`计时
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> TokenBucket {
+        self.state.get_mut().last_refill = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// 当前剩余的令牌数
+    pub fn available(&self) -> u64 {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        state.tokens as u64
+    }
+
+    fn refill(&self, state: &mut State) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity as f64);
+        state.last_refill = now;
+    }
+}
+
+impl Limiter for TokenBucket {
+    fn try_acquire(&self, n: u64) -> Result<(), LimitError> {
+        check_capacity(n, self.capacity)?;
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        let missing = n as f64 - state.tokens;
+        if missing > 0f64 {
+            return Err(LimitError::Limited(Duration::from_secs_f64(
+                missing / self.rate,
+            )));
+        }
+        state.tokens -= n as f64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+
+    #[test]
+    fn test_token_bucket() {
+        let clock = MockClock::new();
+        let bucket = TokenBucket::new(10, 5.0).unwrap().with_clock(clock.clone());
+        // 一开始可以突发用完
+        assert!(bucket.try_acquire(10).is_ok());
+        assert_eq!(
+            bucket.try_acquire(1),
+            Err(LimitError::Limited(Duration::from_millis(200)))
+        );
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(bucket.available(), 3);
+        assert!(bucket.try_acquire(3).is_ok());
+        // 最多攒到容量
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(bucket.available(), 10);
+        assert!(matches!(
+            bucket.try_acquire(11),
+            Err(LimitError::ExceedsCapacity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert!(TokenBucket::new(u64::MAX, 1e-300).is_err());
+        assert!(TokenBucket::new(u64::MAX, 10.0).is_ok());
+    }
+
+    #[cfg(feature = "breaker_async")]
+    #[tokio::test]
+    async fn test_acquire_waits() {
+        let bucket = TokenBucket::new(1, 50.0).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            bucket.acquire(1).await.unwrap();
+        }
+        // 第一个令牌是现成的, 后两个各等 20ms
+        assert!(start.elapsed() >= Duration::from_millis(35));
+        assert!(bucket.acquire(2).await.is_err());
+    }
+}
@@ -1,4 +1,20 @@
+pub mod atomic_rolling;
 pub mod breaker;
 mod bucket;
+#[cfg(feature = "breaker_async")]
+pub mod bulkhead;
+pub mod call;
+pub mod clock;
+pub mod fallback;
+pub mod group;
+pub mod latency;
+#[cfg(feature = "breaker_tower")]
+pub mod layer;
+pub mod limiter;
+#[cfg(feature = "breaker_async")]
+pub mod pipeline;
+#[cfg(feature = "breaker_async")]
+pub mod retry;
 pub mod rolling;
+pub mod three_state;
 mod window;
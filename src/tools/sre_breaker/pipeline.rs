@@ -0,0 +1,230 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use super::breaker::SreBreaker;
+use super::bulkhead::{Bulkhead, BulkheadError};
+use super::call::{Breaker, CallError};
+use super::retry::{RetryError, RetryPolicy};
+
+/// 经过`Pipeline`调用时的错误
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError<E> {
+    /// 熔断器拒绝了请求
+    #[error("circuitbreaker: not allowed for circuit open")]
+    Rejected,
+    /// 没有可用的熔断器, 见`CallError::Unavailable`
+    #[error("circuitbreaker: unavailable: {0:#}")]
+    Unavailable(anyhow::Error),
+    /// 舱壁满了或者排队超时
+    #[error(transparent)]
+    Bulkhead(#[from] BulkheadError),
+    /// 重试超过了`deadline`
+    #[error("retry: deadline exceeded")]
+    DeadlineExceeded,
+    /// 调用本身返回的错误, 重试时是最后一次的错误
+    #[error(transparent)]
+    Inner(E),
+}
+
+impl<E> PipelineError<E> {
+    /// 调用本身返回的错误, 被熔断器或舱壁拒绝, 或者超时时返回`None`
+    pub fn into_inner(self) -> Option<E> {
+        match self {
+            PipelineError::Inner(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<E> From<CallError<E>> for PipelineError<E> {
+    fn from(err: CallError<E>) -> Self {
+        match err {
+            CallError::Rejected => PipelineError::Rejected,
+            CallError::Unavailable(err) => PipelineError::Unavailable(err),
+            CallError::Inner(err) => PipelineError::Inner(err),
+        }
+    }
+}
+
+/// 把重试, 熔断和舱壁组合在一起, 每个都是可选的. 从外到内依次是:
+///
+/// 重试 -> 舱壁 -> 熔断器 -> 调用
+///
+/// 每次重试都重新排队和检查熔断器. 被熔断器或者舱壁拒绝时不再重试,
+/// 舱壁拒绝的请求没有到达下游, 不计入熔断器
+pub struct Pipeline<B = SreBreaker> {
+    retry: Option<RetryPolicy>,
+    bulkhead: Option<Arc<Bulkhead>>,
+    breaker: Option<Arc<B>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            retry: None,
+            bulkhead: None,
+            breaker: None,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<B: Breaker> Pipeline<B> {
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// 同一个下游的多个`Pipeline`可以共用一个舱壁
+    pub fn bulkhead(mut self, bulkhead: Arc<Bulkhead>) -> Self {
+        self.bulkhead = Some(bulkhead);
+        self
+    }
+
+    pub fn breaker<B2: Breaker>(self, breaker: Arc<B2>) -> Pipeline<B2> {
+        Pipeline {
+            retry: self.retry,
+            bulkhead: self.bulkhead,
+            breaker: Some(breaker),
+        }
+    }
+
+    /// 按顺序经过重试, 舱壁和熔断器执行`op`
+    pub async fn call<F, Fut, T, E>(&self, op: F) -> Result<T, PipelineError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.call_if(op, |_| true).await
+    }
+
+    /// `call`加上错误分类, 只有`should_retry`返回`true`的错误才重试
+    pub async fn call_if<F, Fut, T, E, C>(
+        &self,
+        mut op: F,
+        mut should_retry: C,
+    ) -> Result<T, PipelineError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        C: FnMut(&E) -> bool,
+    {
+        let mut attempt = || self.attempt(op());
+        match &self.retry {
+            Some(retry) => retry
+                .retry_if(attempt, |err| match err {
+                    PipelineError::Inner(err) => should_retry(err),
+                    _ => false,
+                })
+                .await
+                .map_err(|err| match err {
+                    RetryError::Inner(err) => err,
+                    RetryError::DeadlineExceeded => PipelineError::DeadlineExceeded,
+                    RetryError::Rejected => PipelineError::Rejected,
+                }),
+            None => attempt().await,
+        }
+    }
+
+    async fn attempt<Fut, T, E>(&self, fut: Fut) -> Result<T, PipelineError<E>>
+    where
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let _permit = match &self.bulkhead {
+            Some(bulkhead) => Some(bulkhead.acquire().await?),
+            None => None,
+        };
+        match &self.breaker {
+            Some(breaker) => Ok(breaker.call(fut).await?),
+            None => fut.await.map_err(PipelineError::Inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::bulkhead::BulkheadBuilder;
+    use crate::sre_breaker::retry::{Backoff, RetryPolicyBuilder};
+    use crate::sre_breaker::three_state::{ThreeStateBreakerBuilder, TripPolicy};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    fn retry() -> RetryPolicy {
+        RetryPolicyBuilder::default()
+            .backoff(Backoff::Fixed(Duration::from_millis(1)))
+            .max_attempts(5)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let breaker = Arc::new(
+            ThreeStateBreakerBuilder::default()
+                .trip(TripPolicy::ConsecutiveFailures(2))
+                .build()
+                .unwrap(),
+        );
+        let pipeline = Pipeline::new()
+            .retry(retry())
+            .bulkhead(Arc::new(Bulkhead::default()))
+            .breaker(breaker);
+        let calls = AtomicU32::new(0);
+        let result = pipeline
+            .call(|| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>("unavailable")
+            })
+            .await;
+        // 失败两次后熔断, 不再重试
+        assert!(matches!(result, Err(PipelineError::Rejected)));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_bulkhead() {
+        let bulkhead = Arc::new(
+            BulkheadBuilder::default()
+                .max_concurrent(1)
+                .build()
+                .unwrap(),
+        );
+        let pipeline = Pipeline::new().retry(retry()).bulkhead(bulkhead.clone());
+        let permit = bulkhead.acquire().await.unwrap();
+        let result = pipeline.call(|| async { Ok::<_, ()>(()) }).await;
+        assert!(matches!(
+            result,
+            Err(PipelineError::Bulkhead(BulkheadError::QueueFull))
+        ));
+        drop(permit);
+
+        let calls = AtomicU32::new(0);
+        let result = pipeline
+            .call(|| async {
+                match calls.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(bulkhead.stats().accepted, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline_deadline() {
+        let retry = RetryPolicyBuilder::default()
+            .deadline(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let pipeline = Pipeline::new().retry(retry);
+        let result = pipeline.call(std::future::pending::<Result<(), ()>>).await;
+        assert!(matches!(result, Err(PipelineError::DeadlineExceeded)));
+    }
+}
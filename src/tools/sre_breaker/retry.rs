@@ -0,0 +1,454 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use super::call::{Breaker, CallError};
+use super::clock::{Clock, Rng};
+use super::rolling::RollingPolicy;
+
+/// 重试的错误
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum RetryError<E> {
+    /// 熔断器拒绝了请求, 不再重试. 不经过熔断器的`retry`不会返回这个错误
+    #[error("circuitbreaker: not allowed for circuit open")]
+    Rejected,
+    /// 超过`deadline`, 正在进行的尝试被取消
+    #[error("retry: deadline exceeded")]
+    DeadlineExceeded,
+    /// 最后一次尝试的错误
+    #[error(transparent)]
+    Inner(E),
+}
+
+impl<E> RetryError<E> {
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, RetryError::Rejected)
+    }
+
+    /// 最后一次尝试的错误, 被拒绝或者超时时返回`None`
+    pub fn into_inner(self) -> Option<E> {
+        match self {
+            RetryError::Inner(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// 重试前等待多久
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    /// 每次等待相同的时间
+    Fixed(Duration),
+    /// 第 n 次重试等待`initial * multiplier^(n-1)`, 不超过`max`
+    Exponential {
+        initial: Duration,
+        multiplier: f64,
+        max: Duration,
+    },
+    /// 在`base`和上次等待时间的 3 倍之间随机, 不超过`max`. 多个客户端同时重试时不会扎堆
+    DecorrelatedJitter { base: Duration, max: Duration },
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            multiplier: 2f64,
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Backoff {
+    /// 第`retry`次重试(从 1 开始)前等待的时间, `prev`是上次等待的时间
+    fn delay(&self, retry: u32, prev: Duration, rng: &Rng) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential {
+                initial,
+                multiplier,
+                max,
+            } => {
+                let factor = multiplier.powi(retry.saturating_sub(1) as i32);
+                Duration::try_from_secs_f64(initial.as_secs_f64() * factor)
+                    .map_or(max, |delay| delay.min(max))
+            }
+            Backoff::DecorrelatedJitter { base, max } => {
+                let upper = prev.saturating_mul(3).max(base).as_secs_f64();
+                let base = base.as_secs_f64();
+                Duration::from_secs_f64(base + (upper - base) * rng.next_f64()).min(max)
+            }
+        }
+    }
+}
+
+/// 重试预算: 窗口内的重试次数不超过正常请求数的`ratio`倍加上`min_retries`,
+/// 下游故障时重试不会把流量放大太多
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    min_retries: u64,
+    requests: RollingPolicy,
+    retries: RollingPolicy,
+    /// 检查和计数要一起做
+    lock: Mutex<()>,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget::new(0.1, 10, Duration::from_secs(10)).unwrap()
+    }
+}
+
+impl RetryBudget {
+    /// `window`内重试不超过请求数的`ratio`倍再加`min_retries`次, 窗口分成 10 个桶
+    pub fn new(ratio: f64, min_retries: u64, window: Duration) -> Result<RetryBudget> {
+        ensure!(ratio >= 0f64 && ratio.is_finite(), "重试比例不能小于 0");
+        Ok(RetryBudget {
+            ratio,
+            min_retries,
+            requests: RollingPolicy::new(10, window / 10)?,
+            retries: RollingPolicy::new(10, window / 10)?,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// 换成`clock`计时
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RetryBudget {
+        self.requests = self.requests.with_clock(clock.clone());
+        self.retries = self.retries.with_clock(clock);
+        self
+    }
+
+    /// 记录一次正常请求(不含重试)
+    pub fn record_request(&self) {
+        self.requests.add(1);
+    }
+
+    /// 预算内时记录一次重试并返回`true`
+    pub fn try_retry(&self) -> bool {
+        let _guard = self.lock.lock();
+        let requests = self.requests.reduce_with_current().1;
+        let retries = self.retries.reduce_with_current().1;
+        if retries as f64 >= self.min_retries as f64 + self.ratio * requests as f64 {
+            return false;
+        }
+        self.retries.add(1);
+        true
+    }
+}
+
+#[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+/// 重试策略
+pub struct RetryPolicy {
+    #[builder(default)]
+    backoff: Backoff,
+    /// 最多尝试几次, 包括第一次
+    #[builder(default = "3")]
+    max_attempts: u32,
+    /// 从第一次开始算的总时间. 超过时取消正在进行的尝试, 返回`RetryError::DeadlineExceeded`,
+    /// 等待后会超过时不再重试, 返回最后一次的错误. 被取消的尝试不计入熔断器.
+    /// 计时, 等待和取消都用 tokio 的时间, 测试时可以用`tokio::time::pause`控制
+    #[builder(setter(strip_option), default)]
+    deadline: Option<Duration>,
+    /// 重试预算, 多个策略可以共用一个
+    #[builder(setter(strip_option), default)]
+    budget: Option<Arc<RetryBudget>>,
+    /// 计算退避时间用的随机数, 见`RetryPolicyBuilder::seed`
+    #[builder(setter(custom), default)]
+    rng: Rng,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicyBuilder::default().build().unwrap()
+    }
+}
+
+impl RetryPolicyBuilder {
+    /// 用固定的种子初始化随机数
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.rng = Some(Rng::seeded(seed));
+        self
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == Some(0) {
+            return Err("最多尝试次数不能为 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl RetryPolicy {
+    /// 执行`op`, 失败时按策略重试, 返回最后一次的结果
+    pub async fn retry<F, Fut, T, E>(&self, op: F) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry_if(op, |_| true).await
+    }
+
+    /// 和`retry`一样, 但只有`should_retry`返回`true`的错误才重试, 如参数错误就不用重试
+    pub async fn retry_if<F, Fut, T, E, C>(
+        &self,
+        mut op: F,
+        should_retry: C,
+    ) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        C: FnMut(&E) -> bool,
+    {
+        self.run(
+            || {
+                let fut = op();
+                async move { fut.await.map_err(CallError::Inner) }
+            },
+            should_retry,
+        )
+        .await
+    }
+
+    /// 每次尝试都经过熔断器, 熔断器拒绝后立即返回`RetryError::Rejected`, 不再重试
+    pub async fn call<B, F, Fut, T, E>(&self, breaker: &B, op: F) -> Result<T, RetryError<E>>
+    where
+        B: Breaker,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.call_if(breaker, op, |_| true).await
+    }
+
+    /// `call`加上错误分类, 见`retry_if`
+    pub async fn call_if<B, F, Fut, T, E, C>(
+        &self,
+        breaker: &B,
+        mut op: F,
+        should_retry: C,
+    ) -> Result<T, RetryError<E>>
+    where
+        B: Breaker,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        C: FnMut(&E) -> bool,
+    {
+        self.run(|| breaker.call(op()), should_retry).await
+    }
+
+    async fn run<F, Fut, T, E, C>(&self, mut op: F, mut should_retry: C) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CallError<E>>>,
+        C: FnMut(&E) -> bool,
+    {
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        if let Some(budget) = &self.budget {
+            budget.record_request();
+        }
+        let mut delay = Duration::ZERO;
+        let mut attempt = 1;
+        loop {
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, op())
+                    .await
+                    .map_err(|_| RetryError::DeadlineExceeded)?,
+                None => op().await,
+            };
+            let err = match result {
+                Ok(value) => return Ok(value),
+                // 熔断器是直接传入的, 不会出现`Unavailable`, 和拒绝一样不再重试
+                Err(CallError::Rejected | CallError::Unavailable(_)) => {
+                    return Err(RetryError::Rejected)
+                }
+                Err(CallError::Inner(err)) => err,
+            };
+            if attempt >= self.max_attempts || !should_retry(&err) {
+                return Err(RetryError::Inner(err));
+            }
+            delay = self.backoff.delay(attempt, delay, &self.rng);
+            if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+                return Err(RetryError::Inner(err));
+            }
+            if self
+                .budget
+                .as_ref()
+                .is_some_and(|budget| !budget.try_retry())
+            {
+                return Err(RetryError::Inner(err));
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::clock::MockClock;
+    use crate::sre_breaker::three_state::{ThreeStateBreakerBuilder, TripPolicy};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn fixed(attempts: u32) -> RetryPolicyBuilder {
+        let mut builder = RetryPolicyBuilder::default();
+        builder
+            .backoff(Backoff::Fixed(Duration::from_millis(1)))
+            .max_attempts(attempts);
+        builder
+    }
+
+    #[test]
+    fn test_backoff() {
+        let rng = Rng::seeded(0);
+        let exp = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max: Duration::from_millis(500),
+        };
+        let delays = (1..=5)
+            .map(|retry| exp.delay(retry, Duration::ZERO, &rng).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+
+        let jitter = Backoff::DecorrelatedJitter {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let mut prev = Duration::ZERO;
+        for retry in 1..=20 {
+            let delay = jitter.delay(retry, prev, &rng);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= (prev * 3).clamp(Duration::from_millis(100), Duration::from_secs(1)));
+            prev = delay;
+        }
+        // 上次等待时间很大时不会溢出
+        assert_eq!(
+            jitter.delay(21, Duration::MAX, &rng),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let calls = AtomicU32::new(0);
+        let op = || async {
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err("timeout"),
+                n => Ok(n),
+            }
+        };
+        assert_eq!(fixed(3).build().unwrap().retry(op).await, Ok(2));
+
+        // 次数用完返回最后一次的错误
+        calls.store(0, Ordering::Relaxed);
+        assert_eq!(
+            fixed(2).build().unwrap().retry(op).await,
+            Err(RetryError::Inner("timeout"))
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // 不可重试的错误不重试
+        calls.store(0, Ordering::Relaxed);
+        let policy = fixed(5).build().unwrap();
+        let result = policy.retry_if(op, |err| *err != "timeout").await;
+        assert_eq!(result, Err(RetryError::Inner("timeout")));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline() {
+        let calls = AtomicU32::new(0);
+        let policy = RetryPolicyBuilder::default()
+            .backoff(Backoff::Fixed(Duration::from_millis(20)))
+            .max_attempts(10)
+            .deadline(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let result = policy
+            .retry(|| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Err::<(), _>(())
+            })
+            .await;
+        // 10ms, 40ms 时各失败一次, 再等 20ms 就超过 50ms, 不再等待
+        assert_eq!(result, Err(RetryError::Inner(())));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(40));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_cancels_attempt() {
+        let calls = AtomicU32::new(0);
+        let policy = RetryPolicyBuilder::default()
+            .backoff(Backoff::Fixed(Duration::from_millis(10)))
+            .max_attempts(10)
+            .deadline(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let result = policy
+            .retry(|| async {
+                if calls.fetch_add(1, Ordering::Relaxed) == 0 {
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                    return Err(());
+                }
+                std::future::pending::<Result<(), ()>>().await
+            })
+            .await;
+        // 第二次尝试一直没有结果, 在剩下的 590ms 用完时取消
+        assert_eq!(result, Err(RetryError::DeadlineExceeded));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_stop_when_breaker_rejects() {
+        let breaker = ThreeStateBreakerBuilder::default()
+            .trip(TripPolicy::ConsecutiveFailures(2))
+            .build()
+            .unwrap();
+        let calls = AtomicU32::new(0);
+        let result = fixed(10)
+            .build()
+            .unwrap()
+            .call(&breaker, || async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>("unavailable")
+            })
+            .await;
+        assert!(result.is_err_and(|err| err.is_rejected()));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_budget() {
+        let clock = MockClock::new();
+        let budget = Arc::new(
+            RetryBudget::new(0.5, 1, Duration::from_secs(10))
+                .unwrap()
+                .with_clock(clock),
+        );
+        let policy = fixed(3).budget(budget.clone()).build().unwrap();
+        let calls = AtomicU32::new(0);
+        for _ in 0..4 {
+            let _ = policy
+                .retry(|| async {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Err::<(), _>(())
+                })
+                .await;
+        }
+        // 4 个请求最多重试 1 + 4 * 0.5 = 3 次
+        assert_eq!(calls.load(Ordering::Relaxed), 4 + 3);
+        assert!(!budget.try_retry());
+    }
+}
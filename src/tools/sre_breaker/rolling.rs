@@ -1,13 +1,24 @@
+use super::clock::{system_clock, Clock};
 use super::window::Window;
+use anyhow::{ensure, Result};
 use parking_lot::RwLock;
 use std::ops::Add;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// 默认的桶数量
+pub const DEFAULT_BUCKETS: usize = 10;
+/// 默认每个桶的时长
+pub const DEFAULT_BUCKET_DURATION: Duration = Duration::from_millis(100);
+
+/// 滑动窗口计数器, 窗口由`size`个桶组成, 每个桶统计`bucket_duration`内的数据.
+/// 每个桶记录添加的值之和与添加的次数, 统计时不包含当前正在写入的桶
 #[derive(Debug)]
-pub(super) struct RollingPolicy {
+pub struct RollingPolicy {
     mutex: RwLock<Inner>,
     size: usize,
     bucket_duration: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug)]
@@ -19,21 +30,42 @@ struct Inner {
 
 impl Default for RollingPolicy {
     fn default() -> Self {
-        RollingPolicy::new(10, Duration::from_millis(100))
+        RollingPolicy::new(DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION).unwrap()
     }
 }
 
+/// 检查窗口配置: 至少 2 个桶(当前桶不参与统计), 每个桶至少 1 毫秒
+pub fn validate_window(size: usize, bucket_duration: Duration) -> Result<()> {
+    ensure!(size >= 2, "滑动窗口至少需要 2 个桶, 当前为 {size}");
+    ensure!(
+        bucket_duration >= Duration::from_millis(1),
+        "桶的时长至少为 1 毫秒, 当前为 {bucket_duration:?}"
+    );
+    ensure!(size <= u32::MAX as usize, "滑动窗口的桶太多, 当前为 {size}");
+    Ok(())
+}
+
 impl RollingPolicy {
-    pub fn new(size: usize, bucket_duration: Duration) -> RollingPolicy {
-        RollingPolicy {
+    pub fn new(size: usize, bucket_duration: Duration) -> Result<RollingPolicy> {
+        validate_window(size, bucket_duration)?;
+        let clock = system_clock();
+        Ok(RollingPolicy {
             size,
             bucket_duration,
             mutex: RwLock::new(Inner {
                 window: Window::new(size),
                 offset: 0,
-                last_append_time: Instant::now(),
+                last_append_time: clock.now(),
             }),
-        }
+            clock,
+        })
+    }
+
+    /// 换成`clock`计时, 窗口从`clock`的当前时间开始
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RollingPolicy {
+        self.mutex.get_mut().last_append_time = clock.now();
+        self.clock = clock;
+        self
     }
 
     /// 往桶中添加一个值, 并更新 offset
@@ -58,14 +90,19 @@ impl RollingPolicy {
                 .last_append_time
                 .add(self.bucket_duration * raw_move_bucket_count as u32);
         }
-        // 对应 bucket 中增加一个值
+        // 对应 bucket 中增加一个值, 滑动过时要加到新的桶里
+        let offset = guard.offset;
         guard.window.add(offset, val);
     }
 
     /// 计算当前滑块滑过了几个 bucket
     pub fn timespan(&self, last_append_time: Instant) -> usize {
         // 这里没有四舍五入, 直接是向下取整的
-        let span = (Instant::now().duration_since(last_append_time).as_millis()
+        let span = (self
+            .clock
+            .now()
+            .duration_since(last_append_time)
+            .as_millis()
             / self.bucket_duration.as_millis()) as i32;
         if span > -1 {
             return span as usize;
@@ -94,28 +131,117 @@ impl RollingPolicy {
     pub fn summary(&self) -> (u64, u64) {
         self.reduce()
     }
+
+    /// 和`reduce`统计的桶相同, 从旧到新把每个桶的 (值之和, 次数) 交给`f`累积, 不分配内存
+    pub fn fold_buckets<B>(&self, init: B, f: impl FnMut(B, (u64, u64)) -> B) -> B {
+        let guard = self.mutex.read();
+        let move_bucket_count = self.timespan(guard.last_append_time);
+        let stat_count = if move_bucket_count == 0 {
+            self.size - 1
+        } else {
+            self.size.saturating_sub(move_bucket_count)
+        };
+        let offset = (guard.offset + move_bucket_count + 1) % self.size;
+        guard.window.iter(offset, stat_count).fold(init, f)
+    }
+
+    /// 统计还在窗口内的所有桶的总和, 包括当前桶
+    pub fn reduce_with_current(&self) -> (u64, u64) {
+        let guard = self.mutex.read();
+        let move_bucket_count = self.timespan(guard.last_append_time);
+        if move_bucket_count >= self.size {
+            return (0, 0);
+        }
+        let offset = (guard.offset + move_bucket_count + 1) % self.size;
+        guard.window.reduce(offset, self.size - move_bucket_count)
+    }
+
+    /// 距离窗口下一次滑动还有多久
+    pub fn until_next_bucket(&self) -> Duration {
+        let guard = self.mutex.read();
+        let elapsed = self.clock.now().duration_since(guard.last_append_time);
+        let passed = elapsed.as_nanos() % self.bucket_duration.as_nanos();
+        self.bucket_duration - Duration::from_nanos(passed as u64)
+    }
+
+    /// 整个窗口的时长
+    pub fn window_span(&self) -> Duration {
+        self.bucket_duration * self.size as u32
+    }
+
+    /// 清空所有桶
+    pub fn reset(&self) {
+        let mut guard = self.mutex.write();
+        guard.window.reset_buckets(0, self.size);
+        guard.offset = 0;
+        guard.last_append_time = self.clock.now();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
-    use std::time::Instant;
+    use crate::sre_breaker::clock::MockClock;
+
+    fn policy(size: usize) -> (RollingPolicy, Arc<MockClock>) {
+        let clock = MockClock::new();
+        let policy = RollingPolicy::new(size, Duration::from_millis(300))
+            .unwrap()
+            .with_clock(clock.clone());
+        (policy, clock)
+    }
 
     #[test]
-    fn test_instant() {
-        let start = Instant::now();
-        thread::sleep(Duration::from_secs(1));
-        let end = Instant::now();
-        let x = end.duration_since(start);
-        println!("{:?}", x);
+    fn test_current_bucket_excluded() {
+        let (r, clock) = policy(10);
+        r.add(1);
+        r.add(0);
+        assert_eq!(r.summary(), (0, 0));
+        // 不满一个桶不会滑动
+        clock.advance(Duration::from_millis(299));
+        assert_eq!(r.summary(), (0, 0));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(r.summary(), (1, 2));
     }
 
     #[test]
     fn test_rolling() {
-        let x = 1.8 as i32;
-        println!("x = {}", x);
-        let r = RollingPolicy::new(10, Duration::from_millis(300)); // 总共 10 个桶, 一个桶 300 ms
-        println!("r = {:?}", r);
+        let (r, clock) = policy(3);
+        for i in 1..=4 {
+            r.add(i);
+            clock.advance(Duration::from_millis(300));
+        }
+        // 3 个桶, 只统计最近滑过的 2 个
+        assert_eq!(r.summary(), (3 + 4, 2));
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(r.summary(), (4, 1));
+        // 长时间没有写入, 整个窗口都过期
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(r.summary(), (0, 0));
+        r.add(5);
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(r.summary(), (5, 1));
+        r.reset();
+        assert_eq!(r.summary(), (0, 0));
+    }
+
+    #[test]
+    fn test_add_after_bucket_switch() {
+        let (r, clock) = policy(3);
+        r.add(1);
+        clock.advance(Duration::from_millis(300));
+        // 滑动后的第一次写入要进新的桶, 不能算到已经滑过的桶里
+        r.add(2);
+        assert_eq!(r.summary(), (1, 1));
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(r.summary(), (3, 2));
+    }
+
+    #[test]
+    fn test_validate_window() {
+        assert!(RollingPolicy::new(1, Duration::from_secs(1)).is_err());
+        assert!(RollingPolicy::new(10, Duration::from_micros(10)).is_err());
+        let r = RollingPolicy::new(60, Duration::from_secs(1)).unwrap();
+        assert_eq!(r.window_span(), Duration::from_secs(60));
     }
 }
@@ -0,0 +1,553 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::breaker::Error;
+use super::call::{Breaker, Permit};
+use super::clock::{system_clock, Clock};
+use super::rolling::{validate_window, RollingPolicy, DEFAULT_BUCKETS, DEFAULT_BUCKET_DURATION};
+
+/// 熔断器的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// 正常放行
+    Closed,
+    /// 熔断中, 拒绝所有请求
+    Open,
+    /// 放少量探测请求, 全部成功就恢复, 有一个失败就重新熔断
+    HalfOpen,
+}
+
+/// 什么时候从`Closed`进入`Open`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TripPolicy {
+    /// 连续失败这么多次
+    ConsecutiveFailures(u32),
+    /// 滑动窗口内请求数不少于`min_requests`, 并且失败率不低于`rate`(0 ~ 1)
+    FailureRate { rate: f64, min_requests: u64 },
+}
+
+impl Default for TripPolicy {
+    fn default() -> Self {
+        TripPolicy::ConsecutiveFailures(5)
+    }
+}
+
+#[derive(Debug, derive_builder::Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+/// 经典的三态熔断器: Closed -> Open -> HalfOpen -> Closed
+pub struct ThreeStateBreaker {
+    /// 熔断条件
+    #[builder(default)]
+    trip: TripPolicy,
+    /// `FailureRate`统计用的滑动窗口的桶数量
+    #[builder(default = "DEFAULT_BUCKETS")]
+    buckets: usize,
+    /// 每个桶的时长, 低 QPS 的下游可以调大, 让窗口内有足够的请求
+    #[builder(default = "DEFAULT_BUCKET_DURATION")]
+    bucket_duration: Duration,
+    /// 熔断多久之后进入半开状态
+    #[builder(default = "Duration::from_secs(10)")]
+    open_timeout: Duration,
+    /// 半开状态最多放行的探测请求数, 这些请求全部成功后关闭熔断.
+    /// 探测请求超过`open_timeout`还没有结果(比如 future 被 drop 了)时, 当作失败重新熔断
+    #[builder(default = "1")]
+    half_open_max_requests: u32,
+    /// 时钟, 默认是系统时钟
+    #[builder(default = "system_clock()")]
+    clock: Arc<dyn Clock>,
+    /// 失败率统计用的滑动窗口
+    #[builder(
+        setter(skip),
+        default = "RollingPolicy::new(self.buckets.unwrap_or(DEFAULT_BUCKETS), self.bucket_duration.unwrap_or(DEFAULT_BUCKET_DURATION)).map_err(|e| e.to_string())?.with_clock(self.clock.clone().unwrap_or_else(system_clock))"
+    )]
+    policy: RollingPolicy,
+    #[builder(setter(skip), default = "Mutex::new(Inner::default())")]
+    inner: Mutex<Inner>,
+    /// 累计执行降级逻辑的次数
+    #[builder(setter(skip), default)]
+    fallbacks: AtomicU64,
+}
+
+/// 三态熔断器的统计快照
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreeStateStats {
+    pub state: State,
+    /// 当前连续失败的次数
+    pub consecutive_failures: u32,
+    /// 窗口内成功的请求数
+    pub accepted: u64,
+    /// 窗口内的总请求数
+    pub total: u64,
+    /// 统计窗口的时长
+    pub window: Duration,
+    /// 累计执行降级逻辑的次数, 不随窗口滑动清零
+    pub fallbacks: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    /// 半开状态已经放行的请求数
+    half_open_requests: u32,
+    /// 半开状态已经成功的请求数
+    half_open_successes: u32,
+    /// 最近一个探测请求的放行时间
+    probe_at: Instant,
+    /// 每次状态切换加 1, 只有当前这一轮放行的请求的结果才会被记录
+    generation: u64,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Inner {
+            state: State::Closed,
+            consecutive_failures: 0,
+            opened_at: Instant::now(),
+            half_open_requests: 0,
+            half_open_successes: 0,
+            probe_at: Instant::now(),
+            generation: 0,
+        }
+    }
+}
+
+impl Default for ThreeStateBreaker {
+    fn default() -> Self {
+        ThreeStateBreaker::new(TripPolicy::default(), Duration::from_secs(10), 1).unwrap()
+    }
+}
+
+impl ThreeStateBreakerBuilder {
+    fn validate(&self) -> Result<(), String> {
+        validate(
+            &self.trip.unwrap_or_default(),
+            self.half_open_max_requests.unwrap_or(1),
+        )?;
+        validate_window(
+            self.buckets.unwrap_or(DEFAULT_BUCKETS),
+            self.bucket_duration.unwrap_or(DEFAULT_BUCKET_DURATION),
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// 半开状态至少要放行 1 个探测请求, 否则永远不会恢复; 失败率必须在 (0, 1] 之间
+fn validate(trip: &TripPolicy, half_open_max_requests: u32) -> Result<(), String> {
+    if half_open_max_requests == 0 {
+        return Err("半开状态的探测请求数不能为 0".to_string());
+    }
+    if let TripPolicy::FailureRate { rate, .. } = trip {
+        if !(rate.is_finite() && *rate > 0f64 && *rate <= 1f64) {
+            return Err(format!("失败率必须在 (0, 1] 之间, 当前为 {rate}"));
+        }
+    }
+    Ok(())
+}
+
+impl ThreeStateBreaker {
+    /// 配置不合法时返回错误, 和`ThreeStateBreakerBuilder`的检查相同
+    pub fn new(
+        trip: TripPolicy,
+        open_timeout: Duration,
+        half_open_max_requests: u32,
+    ) -> anyhow::Result<ThreeStateBreaker> {
+        validate(&trip, half_open_max_requests).map_err(anyhow::Error::msg)?;
+        Ok(ThreeStateBreaker {
+            trip,
+            buckets: DEFAULT_BUCKETS,
+            bucket_duration: DEFAULT_BUCKET_DURATION,
+            open_timeout,
+            half_open_max_requests,
+            clock: system_clock(),
+            policy: RollingPolicy::default(),
+            inner: Mutex::new(Inner::default()),
+            fallbacks: AtomicU64::new(0),
+        })
+    }
+
+    /// 当前状态, 熔断超时后会显示为`HalfOpen`
+    pub fn state(&self) -> State {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// 当前的统计快照
+    pub fn stats(&self) -> ThreeStateStats {
+        let (state, consecutive_failures) = {
+            let mut inner = self.inner.lock();
+            self.refresh(&mut inner);
+            (inner.state, inner.consecutive_failures)
+        };
+        let (accepted, total) = self.policy.summary();
+        ThreeStateStats {
+            state,
+            consecutive_failures,
+            accepted,
+            total,
+            window: self.bucket_duration * self.buckets as u32,
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 通过时返回的凭证要交给`mark_success`/`mark_failed`, 熔断器用它丢掉上一轮放行的请求的结果,
+    /// 比如熔断前发出的慢请求在半开状态才返回, 不能当作探测结果
+    pub fn allow(&self) -> Result<Permit, Error> {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        let permit = Permit {
+            generation: inner.generation,
+        };
+        match inner.state {
+            State::Closed => Ok(permit),
+            State::Open => Err(Error::CircuitOpenError),
+            State::HalfOpen => {
+                if inner.half_open_requests >= self.half_open_max_requests {
+                    return Err(Error::CircuitOpenError);
+                }
+                inner.half_open_requests += 1;
+                inner.probe_at = self.clock.now();
+                Ok(permit)
+            }
+        }
+    }
+
+    pub fn mark_success(&self, permit: Permit) {
+        let mut inner = self.inner.lock();
+        if permit.generation != inner.generation {
+            return;
+        }
+        match inner.state {
+            State::Closed => {
+                inner.consecutive_failures = 0;
+                self.policy.add(1);
+            }
+            State::HalfOpen => {
+                inner.half_open_successes += 1;
+                if inner.half_open_successes >= self.half_open_max_requests {
+                    self.close(&mut inner);
+                }
+            }
+            // 熔断时换了一轮, 不会有这一轮的凭证
+            State::Open => {}
+        }
+    }
+
+    pub fn mark_failed(&self, permit: Permit) {
+        let mut inner = self.inner.lock();
+        if permit.generation != inner.generation {
+            return;
+        }
+        match inner.state {
+            State::Closed => {
+                inner.consecutive_failures += 1;
+                self.policy.add(0);
+                if self.should_trip(&inner) {
+                    self.open(&mut inner);
+                }
+            }
+            State::HalfOpen => self.open(&mut inner),
+            State::Open => {}
+        }
+    }
+
+    /// 记录一次降级, 见`Fallback`
+    pub fn mark_fallback(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn should_trip(&self, inner: &Inner) -> bool {
+        match self.trip {
+            TripPolicy::ConsecutiveFailures(n) => inner.consecutive_failures >= n,
+            TripPolicy::FailureRate { rate, min_requests } => {
+                let (accept, total) = self.policy.summary();
+                total >= min_requests.max(1) && (total - accept) as f64 >= rate * total as f64
+            }
+        }
+    }
+
+    /// 熔断超时后进入半开状态; 半开状态下探测请求迟迟没有结果时重新熔断,
+    /// 否则结果丢失的探测请求会一直占着名额
+    fn refresh(&self, inner: &mut Inner) {
+        let now = self.clock.now();
+        if inner.state == State::HalfOpen
+            && inner.half_open_requests > inner.half_open_successes
+            && now.duration_since(inner.probe_at) >= self.open_timeout
+        {
+            self.open(inner);
+        }
+        if inner.state == State::Open && now.duration_since(inner.opened_at) >= self.open_timeout {
+            inner.state = State::HalfOpen;
+            inner.half_open_requests = 0;
+            inner.half_open_successes = 0;
+            inner.generation += 1;
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.state = State::Open;
+        inner.opened_at = self.clock.now();
+        inner.generation += 1;
+    }
+
+    fn close(&self, inner: &mut Inner) {
+        inner.state = State::Closed;
+        inner.consecutive_failures = 0;
+        inner.generation += 1;
+        // 熔断前的失败不能再算进来, 否则马上又会熔断
+        self.policy.reset();
+    }
+}
+
+impl Breaker for ThreeStateBreaker {
+    fn allow(&self) -> Result<Permit, Error> {
+        ThreeStateBreaker::allow(self)
+    }
+
+    fn mark_success(&self, permit: Permit) {
+        ThreeStateBreaker::mark_success(self, permit)
+    }
+
+    fn mark_failed(&self, permit: Permit) {
+        ThreeStateBreaker::mark_failed(self, permit)
+    }
+
+    fn mark_fallback(&self) {
+        ThreeStateBreaker::mark_fallback(self)
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sre_breaker::breaker::SreBreaker;
+    use crate::sre_breaker::clock::MockClock;
+
+    fn breaker(trip: TripPolicy) -> (ThreeStateBreaker, Arc<MockClock>) {
+        let clock = MockClock::new();
+        let breaker = ThreeStateBreakerBuilder::default()
+            .trip(trip)
+            .open_timeout(Duration::from_millis(50))
+            .half_open_max_requests(2)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        (breaker, clock)
+    }
+
+    /// 放行并记录一次失败
+    fn fail(breaker: &ThreeStateBreaker) {
+        breaker.mark_failed(breaker.allow().unwrap());
+    }
+
+    fn succeed(breaker: &ThreeStateBreaker) {
+        breaker.mark_success(breaker.allow().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(ThreeStateBreakerBuilder::default()
+            .half_open_max_requests(0)
+            .build()
+            .is_err());
+        for rate in [0f64, -0.5, 1.5, f64::NAN, f64::INFINITY] {
+            let trip = TripPolicy::FailureRate {
+                rate,
+                min_requests: 10,
+            };
+            assert!(ThreeStateBreakerBuilder::default()
+                .trip(trip)
+                .build()
+                .is_err());
+            assert!(ThreeStateBreaker::new(trip, Duration::from_secs(1), 1).is_err());
+        }
+        let trip = TripPolicy::FailureRate {
+            rate: 1.0,
+            min_requests: 10,
+        };
+        assert!(ThreeStateBreaker::new(trip, Duration::from_secs(1), 1).is_ok());
+        assert!(ThreeStateBreaker::new(trip, Duration::from_secs(1), 0).is_err());
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let (breaker, _) = breaker(TripPolicy::ConsecutiveFailures(3));
+        fail(&breaker);
+        fail(&breaker);
+        succeed(&breaker);
+        fail(&breaker);
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Closed);
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Open);
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn test_half_open_recover() {
+        let (breaker, clock) = breaker(TripPolicy::ConsecutiveFailures(1));
+        fail(&breaker);
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(breaker.state(), State::Open);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        // 只放行 2 个探测请求
+        let first = breaker.allow().unwrap();
+        let second = breaker.allow().unwrap();
+        assert!(breaker.allow().is_err());
+        breaker.mark_success(first);
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.mark_success(second);
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn test_half_open_failed() {
+        let (breaker, clock) = breaker(TripPolicy::ConsecutiveFailures(1));
+        fail(&breaker);
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(breaker.state(), State::Open);
+        clock.advance(Duration::from_millis(1));
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Open);
+        assert!(breaker.allow().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_half_open_probe_dropped() {
+        let (breaker, clock) = breaker(TripPolicy::ConsecutiveFailures(1));
+        fail(&breaker);
+        clock.advance(Duration::from_millis(50));
+        // 探测请求执行到一半被取消, 结果不会被记录
+        for _ in 0..2 {
+            let probe = breaker.call(std::future::pending::<Result<(), ()>>());
+            assert!(tokio::time::timeout(Duration::ZERO, probe).await.is_err());
+        }
+        assert!(breaker.allow().is_err());
+        assert_eq!(breaker.state(), State::HalfOpen);
+
+        // 超过 open_timeout 还没有结果, 重新熔断, 之后可以再次探测
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(breaker.state(), State::Open);
+        clock.advance(Duration::from_millis(50));
+        succeed(&breaker);
+        succeed(&breaker);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let (breaker, clock) = breaker(TripPolicy::FailureRate {
+            rate: 0.5,
+            min_requests: 10,
+        });
+        for i in 0..20 {
+            if i % 3 == 0 {
+                fail(&breaker);
+            } else {
+                succeed(&breaker);
+            }
+        }
+        // 当前桶不参与统计
+        clock.advance(Duration::from_millis(100));
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Closed);
+
+        for _ in 0..20 {
+            fail(&breaker);
+        }
+        clock.advance(Duration::from_millis(100));
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn test_failure_rate_window() {
+        let clock = MockClock::new();
+        // 低 QPS 的下游, 1 分钟的窗口
+        let breaker = ThreeStateBreakerBuilder::default()
+            .trip(TripPolicy::FailureRate {
+                rate: 0.5,
+                min_requests: 4,
+            })
+            .buckets(6)
+            .bucket_duration(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(breaker.stats().window, Duration::from_secs(60));
+        for _ in 0..4 {
+            fail(&breaker);
+            clock.advance(Duration::from_secs(10));
+        }
+        fail(&breaker);
+        assert_eq!(breaker.state(), State::Open);
+
+        assert!(ThreeStateBreakerBuilder::default()
+            .buckets(1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_half_open_rejection_not_counted() {
+        let (breaker, clock) = breaker(TripPolicy::ConsecutiveFailures(1));
+        fail(&breaker);
+        clock.advance(Duration::from_millis(49));
+        assert_eq!(breaker.state(), State::Open);
+        clock.advance(Duration::from_millis(1));
+        assert!(breaker.call_sync(|| Ok::<_, ()>(())).is_ok());
+        let probe = breaker.allow().unwrap();
+        // 探测名额用完被拒绝, 不能让熔断器重新打开
+        assert!(breaker
+            .call_sync(|| Ok::<_, ()>(()))
+            .is_err_and(|err| err.is_rejected()));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.mark_success(probe);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_stale_result_ignored() {
+        let (breaker, clock) = breaker(TripPolicy::ConsecutiveFailures(1));
+        // 熔断前放行的慢请求
+        let slow = breaker.allow().unwrap();
+        fail(&breaker);
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        // 半开之后才返回, 不算探测结果
+        breaker.mark_success(slow);
+        breaker.mark_success(slow);
+        assert_eq!(breaker.state(), State::HalfOpen);
+        let probe = breaker.allow().unwrap();
+        breaker.mark_failed(slow);
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.mark_failed(probe);
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn test_choose_per_dependency() {
+        let breakers: Vec<Arc<dyn Breaker + Send + Sync>> = vec![
+            Arc::new(SreBreaker::default()),
+            Arc::new(breaker(TripPolicy::ConsecutiveFailures(1)).0),
+        ];
+        for breaker in &breakers {
+            assert_eq!(breaker.call_sync(|| Ok::<_, ()>(1)).unwrap(), 1);
+        }
+        assert!(breakers[1].call_sync(|| Err::<(), _>(())).is_err());
+        assert!(breakers[1]
+            .call_sync(|| Ok::<_, ()>(()))
+            .is_err_and(|err| err.is_rejected()));
+    }
+}
@@ -45,4 +45,12 @@ impl Window {
         }
         (accept, total)
     }
+
+    /// 从 start 开始, 经过 count 个桶, 依次返回每个桶的 (sum, total)
+    pub(super) fn iter(&self, start: usize, count: usize) -> impl Iterator<Item = (u64, u64)> + '_ {
+        (0..count).map(move |offset| {
+            let bucket = &self.buckets[(start + offset) % self.size];
+            (bucket.sum, bucket.total)
+        })
+    }
 }
@@ -3,6 +3,9 @@ use std::sync::OnceLock;
 pub use calamine::{self, CellType, Range};
 pub use regex::{self, Regex};
 
+#[cfg(feature = "zip")]
+pub mod zipped;
+
 #[derive(Debug, Default)]
 pub struct CellCoordinates {
     pub row: u32,
@@ -0,0 +1,162 @@
+use std::io::{Cursor, Read, Seek};
+
+use anyhow::{anyhow, Result};
+use calamine::{open_workbook_auto_from_rs, Data, Range, Reader, Sheets};
+
+use super::SheetRange;
+use crate::zip::ZipReader;
+
+/// 按扩展名识别的表格文件
+const SPREADSHEET_EXTENSIONS: [&str; 5] = ["xlsx", "xlsm", "xlsb", "xls", "ods"];
+
+/// 压缩包中的一个表格文件
+pub struct ZipWorkbook {
+    /// 在压缩包中的文件名
+    pub name: String,
+    pub workbook: Sheets<Cursor<Vec<u8>>>,
+}
+
+/// 压缩包中某个表格文件的一个工作表
+pub struct ZipSheet {
+    /// 在压缩包中的文件名
+    pub entry_name: String,
+    pub sheet_name: String,
+    pub range: Range<Data>,
+}
+
+impl ZipSheet {
+    pub fn sheet_range(&self) -> SheetRange<'_, Data> {
+        SheetRange::new(&self.range)
+    }
+}
+
+/// 打开压缩包中所有的表格文件, 只在内存中读取, 不会解压到磁盘.
+/// 其他文件, 以及 macOS 的`__MACOSX/`和 Office 的`~$`临时文件会被跳过
+pub fn open_workbooks_in_zip<R: Read + Seek>(
+    zip_reader: &mut ZipReader<R>,
+) -> Result<Vec<ZipWorkbook>> {
+    zip_reader.check_limits()?;
+    let mut workbooks = Vec::new();
+    for name in zip_reader.file_names()? {
+        if !is_spreadsheet(&name) {
+            continue;
+        }
+        let content = zip_reader.read_file(&name)?;
+        let workbook = open_workbook_auto_from_rs(Cursor::new(content))
+            .map_err(|e| anyhow!("打开表格失败: {name}, {e}"))?;
+        workbooks.push(ZipWorkbook { name, workbook });
+    }
+    Ok(workbooks)
+}
+
+/// 读取压缩包中所有表格文件的所有工作表
+pub fn read_sheets_in_zip<R: Read + Seek>(zip_reader: &mut ZipReader<R>) -> Result<Vec<ZipSheet>> {
+    let mut sheets = Vec::new();
+    for mut workbook in open_workbooks_in_zip(zip_reader)? {
+        for (sheet_name, range) in workbook.workbook.worksheets() {
+            sheets.push(ZipSheet {
+                entry_name: workbook.name.clone(),
+                sheet_name,
+                range,
+            });
+        }
+    }
+    Ok(sheets)
+}
+
+fn is_spreadsheet(name: &str) -> bool {
+    if name.ends_with('/') || name.starts_with("__MACOSX/") {
+        return false;
+    }
+    let file_name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    if file_name.starts_with("~$") || file_name.starts_with("._") {
+        return false;
+    }
+    file_name.rsplit_once('.').is_some_and(|(_, ext)| {
+        SPREADSHEET_EXTENSIONS
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(ext))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::{InnerZipFileInfo, ZipFileInfo};
+
+    /// 只有一个工作表的最小 xlsx
+    fn xlsx(sheet_name: &str, text: &str, number: f64) -> Vec<u8> {
+        let file = |name: &str, content: String| {
+            InnerZipFileInfo::new(name.to_string(), Some(content.into_bytes()))
+        };
+        ZipFileInfo::new(vec![
+            file(
+                "[Content_Types].xml",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/><Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/></Types>"#.to_string(),
+            ),
+            file(
+                "_rels/.rels",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#.to_string(),
+            ),
+            file(
+                "xl/workbook.xml",
+                format!(r#"<?xml version="1.0" encoding="UTF-8"?><workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets><sheet name="{sheet_name}" sheetId="1" r:id="rId1"/></sheets></workbook>"#),
+            ),
+            file(
+                "xl/_rels/workbook.xml.rels",
+                r#"<?xml version="1.0" encoding="UTF-8"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/></Relationships>"#.to_string(),
+            ),
+            file(
+                "xl/worksheets/sheet1.xml",
+                format!(r#"<?xml version="1.0" encoding="UTF-8"?><worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData><row r="1"><c r="A1" t="inlineStr"><is><t>{text}</t></is></c><c r="B1"><v>{number}</v></c></row></sheetData></worksheet>"#),
+            ),
+        ])
+        .zip_file_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_sheets_in_zip() {
+        let upload = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("readme.txt".to_string(), Some(b"readme".to_vec())),
+            InnerZipFileInfo::new(
+                "a/报关单.xlsx".to_string(),
+                Some(xlsx("报关", "hello", 42.0)),
+            ),
+            InnerZipFileInfo::new(
+                "__MACOSX/a/._报关单.xlsx".to_string(),
+                Some(b"junk".to_vec()),
+            ),
+            InnerZipFileInfo::new("b.XLSX".to_string(), Some(xlsx("Sheet1", "world", 1.5))),
+        ])
+        .zip_file_bytes()
+        .unwrap();
+
+        let mut zip_reader = ZipReader::from_bytes(&upload).unwrap();
+        let sheets = read_sheets_in_zip(&mut zip_reader).unwrap();
+        assert_eq!(sheets.len(), 2);
+        assert_eq!(sheets[0].entry_name, "a/报关单.xlsx");
+        assert_eq!(sheets[0].sheet_name, "报关");
+        let range = sheets[0].sheet_range();
+        assert_eq!(
+            range.get_value("A1"),
+            Some(&Data::String("hello".to_string()))
+        );
+        assert_eq!(range.get_value("B1"), Some(&Data::Float(42.0)));
+        assert_eq!(sheets[1].entry_name, "b.XLSX");
+        assert_eq!(
+            sheets[1].sheet_range().get_value("A1"),
+            Some(&Data::String("world".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_is_spreadsheet() {
+        assert!(is_spreadsheet("data/2024.xlsx"));
+        assert!(is_spreadsheet("old.XLS"));
+        assert!(!is_spreadsheet("data/~$2024.xlsx"));
+        assert!(!is_spreadsheet("__MACOSX/data/._2024.xlsx"));
+        assert!(!is_spreadsheet("xlsx/"));
+        assert!(!is_spreadsheet("notes.txt"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use zip::DateTime;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// `SystemTime`转成 zip 的 MS-DOS 时间(UTC), 超出 1980 ~ 2107 年的返回`None`
+pub(super) fn from_system_time(time: SystemTime) -> Option<DateTime> {
+    let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(SECONDS_PER_DAY));
+    let secs_of_day = secs.rem_euclid(SECONDS_PER_DAY);
+    DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (secs_of_day / 3600) as u8,
+        (secs_of_day % 3600 / 60) as u8,
+        (secs_of_day % 60) as u8,
+    )
+    .ok()
+}
+
+/// zip 的 MS-DOS 时间(按 UTC 处理)转成`SystemTime`
+pub(super) fn to_system_time(time: DateTime) -> SystemTime {
+    let days = days_from_civil(time.year() as i64, time.month() as i64, time.day() as i64);
+    let secs = days * SECONDS_PER_DAY
+        + time.hour() as i64 * 3600
+        + time.minute() as i64 * 60
+        + time.second() as i64;
+    // MS-DOS 时间从 1980 年开始, 不会早于 UNIX_EPOCH
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+/// 1970-01-01 以来的天数转成年月日, 见 http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// 年月日转成 1970-01-01 以来的天数
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+
+use anyhow::{anyhow, Result};
+use encoding_rs::{Encoding, GB18030, GBK, SHIFT_JIS};
+use zip::read::ZipFile;
+
+/// zip 中文件名的编码
+///
+/// 中文 Windows 自带的压缩工具会用 GBK 保存文件名, 而且不设置 UTF-8 标志,
+/// 直接按 UTF-8 / CP437 解码就会乱码
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilenameEncoding {
+    /// 读取时: 合法的 UTF-8 按 UTF-8 处理, 否则按 GB18030 处理;
+    /// 写入时等同于`Utf8`
+    #[default]
+    Auto,
+    Utf8,
+    Gbk,
+    /// GBK 的超集
+    Gb18030,
+    ShiftJis,
+}
+
+impl FilenameEncoding {
+    fn encoding(self) -> Option<&'static Encoding> {
+        match self {
+            FilenameEncoding::Auto | FilenameEncoding::Utf8 => None,
+            FilenameEncoding::Gbk => Some(GBK),
+            FilenameEncoding::Gb18030 => Some(GB18030),
+            FilenameEncoding::ShiftJis => Some(SHIFT_JIS),
+        }
+    }
+
+    /// 写入时是否使用 UTF-8, 使用 UTF-8 时需要设置 UTF-8 标志
+    pub fn is_utf8(self) -> bool {
+        self.encoding().is_none()
+    }
+
+    /// 解码 zip 中的原始文件名或注释. 设置了 UTF-8 标志(通用标志位 11)时总是按 UTF-8 解码,
+    /// 不管指定的是什么编码
+    pub fn decode(self, raw: &[u8], utf8_flag: bool) -> String {
+        if utf8_flag {
+            return String::from_utf8_lossy(raw).into_owned();
+        }
+        match (self, std::str::from_utf8(raw)) {
+            (FilenameEncoding::Auto | FilenameEncoding::Utf8, Ok(name)) => name.to_string(),
+            (FilenameEncoding::Auto, Err(_)) => {
+                GB18030.decode_without_bom_handling(raw).0.into_owned()
+            }
+            (FilenameEncoding::Utf8, Err(_)) => String::from_utf8_lossy(raw).into_owned(),
+            (encoding, _) => encoding
+                .encoding()
+                .unwrap()
+                .decode_without_bom_handling(raw)
+                .0
+                .into_owned(),
+        }
+    }
+
+    /// 解码压缩包中条目的文件名
+    pub(super) fn file_name(self, file: &ZipFile<'_>) -> String {
+        self.decode(file.name_raw(), has_utf8_flag(file))
+    }
+
+    /// 按当前编码编码文件名, 有无法编码的字符时返回错误
+    pub fn encode(self, name: &str) -> Result<Cow<'_, [u8]>> {
+        let Some(encoding) = self.encoding() else {
+            return Ok(Cow::Borrowed(name.as_bytes()));
+        };
+        let (bytes, _, had_errors) = encoding.encode(name);
+        if had_errors {
+            return Err(anyhow!("`{name}`无法使用{}编码", encoding.name()));
+        }
+        Ok(bytes)
+    }
+}
+
+/// `zip`没有直接提供通用标志位. 没有 UTF-8 标志时`ZipFile::name`是按 CP437 解码的,
+/// 非 ASCII 字节都会变成多字节字符, 所以只有设置了标志时才会和原始文件名相同
+fn has_utf8_flag(file: &ZipFile<'_>) -> bool {
+    !file.name_raw().is_ascii() && file.name().as_bytes() == file.name_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let gbk = GBK.encode("报关单/申报.xml").0;
+        assert_eq!(
+            FilenameEncoding::Auto.decode(&gbk, false),
+            "报关单/申报.xml"
+        );
+        assert_eq!(FilenameEncoding::Gbk.decode(&gbk, false), "报关单/申报.xml");
+        assert_eq!(
+            FilenameEncoding::Auto.decode("中文.txt".as_bytes(), false),
+            "中文.txt"
+        );
+        let sjis = SHIFT_JIS.encode("日本語.txt").0;
+        assert_eq!(
+            FilenameEncoding::ShiftJis.decode(&sjis, false),
+            "日本語.txt"
+        );
+        // UTF-8 标志优先于指定的编码
+        assert_eq!(
+            FilenameEncoding::Gbk.decode("中文.txt".as_bytes(), true),
+            "中文.txt"
+        );
+        assert!(FilenameEncoding::Gbk.encode("한국어").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::io::{self, Read, Seek};
+
+use anyhow::Result;
+use zip::CompressionMethod;
+
+use super::limits::{unwrap_limit_error, LimitedReader, ZipLimitError};
+use super::{DateTime, ZipReader};
+
+/// 压缩包中一个条目的信息, 不需要解压就能拿到
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ZipEntryInfo {
+    pub name: String,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub crc32: u32,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_display"))]
+    pub compression_method: CompressionMethod,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_datetime"))]
+    pub last_modified: Option<DateTime>,
+    pub encrypted: bool,
+    pub is_dir: bool,
+}
+
+/// 单个条目的校验结果
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "status", rename_all = "snake_case"))]
+pub enum VerifyStatus {
+    Ok,
+    /// 加密的条目没有密码, 无法校验
+    Encrypted,
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// 解压失败, 比如不支持的压缩方式或者数据损坏
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ZipEntryVerification {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub entry: ZipEntryInfo,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub status: VerifyStatus,
+}
+
+/// 整个压缩包的校验结果, 可以直接序列化后写审计日志
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ZipVerifyReport {
+    pub comment: String,
+    pub entries: Vec<ZipEntryVerification>,
+}
+
+impl ZipVerifyReport {
+    /// 所有条目都校验通过, 加密的条目视为未通过
+    pub fn is_ok(&self) -> bool {
+        self.entries.iter().all(|v| v.status == VerifyStatus::Ok)
+    }
+
+    /// 校验未通过的条目
+    pub fn failures(&self) -> impl Iterator<Item = &ZipEntryVerification> {
+        self.entries.iter().filter(|v| v.status != VerifyStatus::Ok)
+    }
+}
+
+impl<R: Read + Seek> ZipReader<R> {
+    /// 列出所有条目, 只读中央目录, 不解压
+    pub fn entries(&mut self) -> Result<Vec<ZipEntryInfo>> {
+        let mut entries = Vec::with_capacity(self.archive.len());
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index_raw(i)?;
+            entries.push(ZipEntryInfo {
+                name: self.encoding.file_name(&file),
+                compressed_size: file.compressed_size(),
+                size: file.size(),
+                crc32: file.crc32(),
+                compression_method: file.compression(),
+                last_modified: file.last_modified(),
+                encrypted: file.encrypted(),
+                is_dir: file.is_dir(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// 解压每个条目并校验 crc 和大小, 单个条目出错不会中断, 结果记录在报告里.
+    /// 超出`ZipLimits`时直接返回`ZipLimitError`
+    pub fn verify(&mut self) -> Result<ZipVerifyReport> {
+        self.check_limits()?;
+        let entries = self.entries()?;
+        let mut results = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.into_iter().enumerate() {
+            let status = if entry.encrypted {
+                VerifyStatus::Encrypted
+            } else {
+                self.verify_entry(i, &entry)?
+            };
+            results.push(ZipEntryVerification { entry, status });
+        }
+        Ok(ZipVerifyReport {
+            comment: self.comment(),
+            entries: results,
+        })
+    }
+
+    fn verify_entry(&mut self, index: usize, entry: &ZipEntryInfo) -> Result<VerifyStatus> {
+        let mut file = match self.archive.by_index(index) {
+            Ok(file) => file,
+            Err(err) => {
+                return Ok(VerifyStatus::Error {
+                    message: err.to_string(),
+                })
+            }
+        };
+        let mut reader = LimitedReader::new(
+            &mut file,
+            &self.limits,
+            &entry.name,
+            entry.compressed_size,
+            &self.total_read,
+        );
+        let mut hasher = CrcWriter::default();
+        // zip 在读到结尾时会校验 crc, 不一致时返回错误, 这里自己再算一遍好区分错误类型
+        let result = io::copy(&mut reader, &mut hasher);
+        if let Err(err) = result {
+            let err = unwrap_limit_error(err);
+            if err.is::<ZipLimitError>() {
+                return Err(err);
+            }
+            let actual = hasher.hasher.finalize();
+            if actual != entry.crc32 {
+                return Ok(VerifyStatus::CrcMismatch {
+                    expected: entry.crc32,
+                    actual,
+                });
+            }
+            return Ok(VerifyStatus::Error {
+                message: err.to_string(),
+            });
+        }
+        if hasher.size != entry.size {
+            return Ok(VerifyStatus::SizeMismatch {
+                expected: entry.size,
+                actual: hasher.size,
+            });
+        }
+        let actual = hasher.hasher.finalize();
+        if actual != entry.crc32 {
+            return Ok(VerifyStatus::CrcMismatch {
+                expected: entry.crc32,
+                actual,
+            });
+        }
+        Ok(VerifyStatus::Ok)
+    }
+}
+
+#[derive(Default)]
+struct CrcWriter {
+    hasher: crc32fast::Hasher,
+    size: u64,
+}
+
+impl io::Write for CrcWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: std::fmt::Display,
+    S: serde::Serializer,
+{
+    serializer.collect_str(value)
+}
+
+#[cfg(feature = "serde")]
+fn serialize_datetime<S>(value: &Option<DateTime>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(v) => serializer.collect_str(&format_args!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            v.year(),
+            v.month(),
+            v.day(),
+            v.hour(),
+            v.minute(),
+            v.second()
+        )),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::stream::{StreamFileOptions, ZipStreamWriter};
+    use std::io::Write;
+
+    fn build_zip() -> Vec<u8> {
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        writer
+            .start_file(
+                "stored.txt",
+                StreamFileOptions::default().compression_method(CompressionMethod::Stored),
+            )
+            .unwrap();
+        writer.write_all(b"hello stored").unwrap();
+        writer
+            .start_file("deflated.txt", StreamFileOptions::default())
+            .unwrap();
+        writer.write_all("hello ".repeat(100).as_bytes()).unwrap();
+        writer
+            .add_directory("dir", StreamFileOptions::default())
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_entries_and_verify() {
+        let bytes = build_zip();
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        let entries = reader.entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, "stored.txt");
+        assert_eq!(entries[0].size, 12);
+        assert_eq!(entries[0].compressed_size, 12);
+        assert_eq!(entries[0].crc32, crc32fast::hash(b"hello stored"));
+        assert_eq!(entries[1].compression_method, CompressionMethod::Deflated);
+        assert_eq!(entries[1].size, 600);
+        assert!(entries[2].is_dir);
+        assert!(!entries[2].encrypted);
+        assert!(reader.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_verify_crc_mismatch() {
+        let mut bytes = build_zip();
+        // 改掉未压缩条目里的一个字节
+        let pos = bytes
+            .windows(12)
+            .position(|v| v == b"hello stored")
+            .unwrap();
+        bytes[pos] = b'j';
+        let report = ZipReader::from_bytes(&bytes).unwrap().verify().unwrap();
+        assert!(!report.is_ok());
+        let failures = report.failures().collect::<Vec<_>>();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].entry.name, "stored.txt");
+        assert_eq!(
+            failures[0].status,
+            VerifyStatus::CrcMismatch {
+                expected: crc32fast::hash(b"hello stored"),
+                actual: crc32fast::hash(b"jello stored"),
+            }
+        );
+    }
+
+    #[cfg(all(feature = "serde", feature = "serde_json"))]
+    #[test]
+    fn test_report_serialize() {
+        let bytes = build_zip();
+        let report = ZipReader::from_bytes(&bytes).unwrap().verify().unwrap();
+        let value = serde_json::to_value(&report).unwrap();
+        let entry = &value["entries"][1];
+        assert_eq!(entry["name"], "deflated.txt");
+        assert_eq!(entry["compression_method"], "Deflated");
+        assert_eq!(entry["last_modified"], "1980-01-01 00:00:00");
+        assert_eq!(entry["status"], "ok");
+    }
+}
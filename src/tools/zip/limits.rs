@@ -0,0 +1,342 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 解压出的数据少于这个值时不检查压缩率, 小文件的压缩率本来就可能很高
+const RATIO_GRACE_SIZE: u64 = 100 * 1024;
+
+/// 读取 zip 时的资源限制, 用来防止 zip 炸弹
+///
+/// 中央目录里声明的大小可能是假的, 所以除了提前检查声明的大小,
+/// 实际解压时也会按解压出来的字节数检查
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZipLimits {
+    /// 最多的条目数量, 包括目录
+    pub max_entries: usize,
+    /// 所有条目解压后的总大小
+    pub max_total_size: u64,
+    /// 单个条目解压后的大小
+    pub max_entry_size: u64,
+    /// 单个条目 解压后大小 / 压缩后大小 的最大值, 解压不足 100KB 时不检查
+    pub max_compression_ratio: f64,
+    /// zip 套 zip 的最大层数, 最外层为 0
+    pub max_nesting_depth: usize,
+}
+
+impl Default for ZipLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_total_size: 4 * 1024 * 1024 * 1024,
+            max_entry_size: 1024 * 1024 * 1024,
+            max_compression_ratio: 100f64,
+            max_nesting_depth: 3,
+        }
+    }
+}
+
+impl ZipLimits {
+    /// 不做任何限制, 只用于可信的压缩包
+    pub fn unlimited() -> Self {
+        Self {
+            max_entries: usize::MAX,
+            max_total_size: u64::MAX,
+            max_entry_size: u64::MAX,
+            max_compression_ratio: f64::INFINITY,
+            max_nesting_depth: usize::MAX,
+        }
+    }
+
+    pub(super) fn check_entries(&self, entries: usize) -> Result<(), ZipLimitError> {
+        if entries > self.max_entries {
+            return Err(ZipLimitError::TooManyEntries {
+                limit: self.max_entries,
+                actual: entries,
+            });
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_total_size(&self, total_size: u64) -> Result<(), ZipLimitError> {
+        if total_size > self.max_total_size {
+            return Err(ZipLimitError::TotalSizeExceeded {
+                limit: self.max_total_size,
+                actual: total_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_entry(
+        &self,
+        name: &str,
+        size: u64,
+        compressed_size: u64,
+    ) -> Result<(), ZipLimitError> {
+        if size > self.max_entry_size {
+            return Err(ZipLimitError::EntrySizeExceeded {
+                name: name.to_string(),
+                limit: self.max_entry_size,
+                actual: size,
+            });
+        }
+        if size > RATIO_GRACE_SIZE {
+            let ratio = size as f64 / compressed_size.max(1) as f64;
+            if ratio > self.max_compression_ratio {
+                return Err(ZipLimitError::CompressionRatioExceeded {
+                    name: name.to_string(),
+                    limit: self.max_compression_ratio,
+                    actual: ratio,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_nesting_depth(&self, depth: usize) -> Result<(), ZipLimitError> {
+        if depth > self.max_nesting_depth {
+            return Err(ZipLimitError::NestingTooDeep {
+                limit: self.max_nesting_depth,
+                actual: depth,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 超过`ZipLimits`中某个限制时的错误, 可以从`anyhow::Error`中`downcast_ref`出来
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ZipLimitError {
+    #[error("zip: too many entries, limit = {limit}, actual = {actual}")]
+    TooManyEntries { limit: usize, actual: usize },
+    #[error("zip: total uncompressed size exceeded, limit = {limit}, actual = {actual}")]
+    TotalSizeExceeded { limit: u64, actual: u64 },
+    #[error("zip: entry `{name}` uncompressed size exceeded, limit = {limit}, actual = {actual}")]
+    EntrySizeExceeded {
+        name: String,
+        limit: u64,
+        actual: u64,
+    },
+    #[error(
+        "zip: entry `{name}` compression ratio exceeded, limit = {limit}, actual = {actual:.1}"
+    )]
+    CompressionRatioExceeded {
+        name: String,
+        limit: f64,
+        actual: f64,
+    },
+    #[error("zip: nesting too deep, limit = {limit}, actual = {actual}")]
+    NestingTooDeep { limit: usize, actual: usize },
+}
+
+impl ZipLimitError {
+    /// 超出的是`ZipLimits`中的哪个字段
+    pub fn limit_name(&self) -> &'static str {
+        match self {
+            ZipLimitError::TooManyEntries { .. } => "max_entries",
+            ZipLimitError::TotalSizeExceeded { .. } => "max_total_size",
+            ZipLimitError::EntrySizeExceeded { .. } => "max_entry_size",
+            ZipLimitError::CompressionRatioExceeded { .. } => "max_compression_ratio",
+            ZipLimitError::NestingTooDeep { .. } => "max_nesting_depth",
+        }
+    }
+}
+
+/// 解压时按实际读出的字节数检查限制, 超出时返回包着`ZipLimitError`的`io::Error`
+pub(super) struct LimitedReader<'a, R> {
+    inner: R,
+    limits: &'a ZipLimits,
+    name: &'a str,
+    compressed_size: u64,
+    read: u64,
+    /// 整个压缩包(包括嵌套的压缩包)已经解压出的字节数
+    total_read: &'a AtomicU64,
+}
+
+impl<'a, R: Read> LimitedReader<'a, R> {
+    pub(super) fn new(
+        inner: R,
+        limits: &'a ZipLimits,
+        name: &'a str,
+        compressed_size: u64,
+        total_read: &'a AtomicU64,
+    ) -> Self {
+        Self {
+            inner,
+            limits,
+            name,
+            compressed_size,
+            read: 0,
+            total_read,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        let total_read = self.total_read.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        self.limits
+            .check_entry(self.name, self.read, self.compressed_size)
+            .and_then(|_| self.limits.check_total_size(total_read))
+            .map_err(io::Error::other)?;
+        Ok(n)
+    }
+}
+
+/// 把`LimitedReader`返回的`io::Error`还原成`ZipLimitError`
+pub(super) fn unwrap_limit_error(err: io::Error) -> anyhow::Error {
+    if err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<ZipLimitError>())
+    {
+        let inner = err.into_inner().unwrap();
+        return anyhow::Error::new(*inner.downcast::<ZipLimitError>().unwrap());
+    }
+    err.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::{InnerZipFileInfo, ZipFileInfo, ZipReader};
+
+    fn limit_error(err: anyhow::Error) -> ZipLimitError {
+        err.downcast::<ZipLimitError>().unwrap()
+    }
+
+    #[test]
+    fn test_too_many_entries() {
+        let bytes = ZipFileInfo::new(
+            (0..5)
+                .map(|i| InnerZipFileInfo::new(format!("{i}.txt"), Some(vec![b'a'])))
+                .collect(),
+        )
+        .zip_file_bytes()
+        .unwrap();
+        let limits = ZipLimits {
+            max_entries: 3,
+            ..Default::default()
+        };
+        let err = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .with_limits(limits)
+            .read_all()
+            .err()
+            .unwrap();
+        let err = limit_error(err);
+        assert_eq!(err.limit_name(), "max_entries");
+        assert_eq!(
+            err,
+            ZipLimitError::TooManyEntries {
+                limit: 3,
+                actual: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        let bytes = ZipFileInfo::from(InnerZipFileInfo::new(
+            "zeros.bin".to_string(),
+            Some(vec![0u8; 10 * 1024 * 1024]),
+        ))
+        .zip_file_bytes()
+        .unwrap();
+        let err = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .verify()
+            .err()
+            .unwrap();
+        assert_eq!(limit_error(err).limit_name(), "max_compression_ratio");
+
+        let report = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .with_limits(ZipLimits::unlimited())
+            .verify()
+            .unwrap();
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn test_limited_reader() {
+        // 声明的大小是假的时候, 按实际读出的字节数检查
+        let limits = ZipLimits {
+            max_entry_size: 100,
+            ..Default::default()
+        };
+        let total_read = AtomicU64::new(0);
+        let mut reader = LimitedReader::new(
+            io::Cursor::new(vec![0u8; 200]),
+            &limits,
+            "fake.bin",
+            200,
+            &total_read,
+        );
+        let err = io::copy(&mut reader, &mut io::sink()).err().unwrap();
+        assert_eq!(
+            limit_error(unwrap_limit_error(err)).limit_name(),
+            "max_entry_size"
+        );
+    }
+
+    #[test]
+    fn test_total_size_across_reads() {
+        let inner = ZipFileInfo::from(InnerZipFileInfo::new(
+            "inner.txt".to_string(),
+            Some(vec![b'a'; 100]),
+        ))
+        .zip_file_bytes()
+        .unwrap();
+        let bytes = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("a.txt".to_string(), Some(vec![b'a'; 100])),
+            InnerZipFileInfo::new("inner.zip".to_string(), Some(inner.clone())),
+        ])
+        .zip_file_bytes()
+        .unwrap();
+        let limits = |max_total_size| ZipLimits {
+            max_total_size,
+            ..Default::default()
+        };
+
+        // 多次读取单个文件时累计计算
+        let mut reader = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .with_limits(limits(150));
+        reader.read_file("a.txt").unwrap();
+        let err = reader.read_file("a.txt").err().unwrap();
+        assert_eq!(limit_error(err).limit_name(), "max_total_size");
+
+        // 嵌套的压缩包和外层共用
+        let limits = limits(150 + inner.len() as u64);
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap().with_limits(limits);
+        reader.read_file("a.txt").unwrap();
+        let mut nested = reader.open_nested("inner.zip").unwrap();
+        let err = nested.read_file("inner.txt").err().unwrap();
+        assert_eq!(limit_error(err).limit_name(), "max_total_size");
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        let mut bytes = b"innermost".to_vec();
+        for i in 0..3 {
+            bytes = ZipFileInfo::from(InnerZipFileInfo::new(format!("{i}.zip"), Some(bytes)))
+                .zip_file_bytes()
+                .unwrap();
+        }
+        let limits = ZipLimits {
+            max_nesting_depth: 1,
+            ..Default::default()
+        };
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap().with_limits(limits);
+        let mut nested = reader.open_nested("2.zip").unwrap();
+        let err = nested.open_nested("1.zip").err().unwrap();
+        assert_eq!(
+            limit_error(err),
+            ZipLimitError::NestingTooDeep {
+                limit: 1,
+                actual: 2
+            }
+        );
+    }
+}
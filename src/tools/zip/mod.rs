@@ -0,0 +1,334 @@
+use std::io::{Read, Seek, Write};
+
+use zip::ZipWriter;
+
+use anyhow::{anyhow, Result};
+
+use crate::archive::{ArchiveBuilder, ArchiveEntry};
+
+mod datetime;
+mod encoding;
+pub mod inspect;
+mod limits;
+mod parallel;
+pub mod read;
+mod split;
+pub mod stream;
+pub mod update;
+
+pub use encoding::FilenameEncoding;
+pub use inspect::{VerifyStatus, ZipEntryInfo, ZipVerifyReport};
+pub use limits::{ZipLimitError, ZipLimits};
+pub use read::ZipReader;
+pub use split::MIN_VOLUME_SIZE;
+use stream::{RawEntry, StreamFileOptions, ZipStreamWriter};
+pub use update::ZipUpdate;
+pub use zip::DateTime;
+
+pub struct InnerZipFileInfo {
+    pub file_name: String,
+    pub file_content: Option<Vec<u8>>,
+    /// 修改时间, 从磁盘读取的文件不设置时使用文件本身的修改时间
+    pub last_modified: Option<DateTime>,
+    /// Unix 权限, 如`0o755`, 从磁盘读取的文件不设置时使用文件本身的权限
+    pub unix_permissions: Option<u32>,
+    /// 文件注释
+    pub comment: Option<String>,
+}
+
+impl InnerZipFileInfo {
+    pub fn new(file_name: String, file_content: Option<Vec<u8>>) -> Self {
+        Self {
+            file_name,
+            file_content,
+            last_modified: None,
+            unix_permissions: None,
+            comment: None,
+        }
+    }
+
+    pub fn with_last_modified(mut self, last_modified: DateTime) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+
+    pub fn with_unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_permissions = Some(mode);
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// 修改时间和权限, 没有显式设置的从磁盘文件上取
+    fn metadata(&self) -> Result<(Option<DateTime>, Option<u32>)> {
+        let mut last_modified = self.last_modified;
+        let mut unix_permissions = self.unix_permissions;
+        if self.file_content.is_none() && (last_modified.is_none() || unix_permissions.is_none()) {
+            let metadata = std::fs::metadata(self.file_name.as_str())?;
+            if last_modified.is_none() {
+                last_modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(datetime::from_system_time);
+            }
+            #[cfg(unix)]
+            if unix_permissions.is_none() {
+                use std::os::unix::fs::PermissionsExt;
+                unix_permissions = Some(metadata.permissions().mode());
+            }
+        }
+        Ok((last_modified, unix_permissions.map(|mode| mode & 0o7777)))
+    }
+
+    fn stream_file_options(&self) -> Result<StreamFileOptions> {
+        let (last_modified, unix_permissions) = self.metadata()?;
+        let mut options = StreamFileOptions::default();
+        if let Some(last_modified) = last_modified {
+            options = options.last_modified_time(last_modified);
+        }
+        if let Some(mode) = unix_permissions {
+            options = options.unix_permissions(mode);
+        }
+        if let Some(comment) = &self.comment {
+            options = options.comment(comment.as_str());
+        }
+        let size = match &self.file_content {
+            Some(content) => content.len() as u64,
+            None => std::fs::metadata(self.file_name.as_str())?.len(),
+        };
+        // 不能压缩的数据用 deflate 会稍微变大, 留出余量
+        Ok(options.large_file(size + size / 1000 + 1024 >= u32::MAX as u64))
+    }
+}
+
+pub struct ZipFileInfo {
+    pub inner: Vec<InnerZipFileInfo>,
+    /// 压缩包注释
+    pub comment: Option<String>,
+    /// 写入时文件名的编码, 默认 UTF-8
+    pub filename_encoding: FilenameEncoding,
+}
+
+impl From<InnerZipFileInfo> for ZipFileInfo {
+    fn from(value: InnerZipFileInfo) -> Self {
+        Self::new(vec![value])
+    }
+}
+
+impl From<ArchiveEntry> for InnerZipFileInfo {
+    fn from(value: ArchiveEntry) -> Self {
+        Self {
+            file_name: value.name,
+            file_content: Some(value.content),
+            last_modified: value.last_modified.and_then(datetime::from_system_time),
+            unix_permissions: value.unix_permissions,
+            comment: None,
+        }
+    }
+}
+
+impl ArchiveBuilder for ZipFileInfo {
+    fn write_to<W: Write>(self, writer: W) -> Result<W> {
+        self.zip_to_writer(writer)
+    }
+}
+
+impl ZipFileInfo {
+    pub fn new(inner: Vec<InnerZipFileInfo>) -> Self {
+        Self {
+            inner,
+            comment: None,
+            filename_encoding: FilenameEncoding::default(),
+        }
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// 用 GBK 之类的编码写文件名, 给不认识 UTF-8 标志的老解压工具用
+    pub fn with_filename_encoding(mut self, encoding: FilenameEncoding) -> Self {
+        self.filename_encoding = encoding;
+        self
+    }
+
+    /// 压缩文件. 文件注释和非 UTF-8 文件名只有`ZipStreamWriter`支持, 这时改用`zip_to_writer`
+    pub fn zip_file_bytes(self) -> Result<Vec<u8>> {
+        if !self.filename_encoding.is_utf8() || self.inner.iter().any(|i| i.comment.is_some()) {
+            return self.zip_to_writer(Vec::with_capacity(102400));
+        }
+        let mut archive = std::io::Cursor::new(Vec::with_capacity(102400));
+        {
+            let zip_writer = ZipWriter::new(&mut archive);
+            self.push_zip_file(zip_writer)?;
+        }
+        Ok(archive.into_inner())
+    }
+
+    /// 流式压缩到任意`Write`, 不需要`Seek`, 可以直接写到 HTTP body 之类的流里
+    pub fn zip_to_writer<W: Write>(self, writer: W) -> Result<W> {
+        let mut zip_writer = ZipStreamWriter::new(writer);
+        self.configure(&mut zip_writer);
+        for item in self.inner {
+            item.write_to_stream(&mut zip_writer)?;
+        }
+        zip_writer.finish()
+    }
+
+    /// 把压缩包注释和文件名编码设置到`ZipStreamWriter`上
+    fn configure<W: Write>(&self, zip_writer: &mut ZipStreamWriter<W>) {
+        zip_writer.set_filename_encoding(self.filename_encoding);
+        if let Some(comment) = &self.comment {
+            zip_writer.set_comment(comment.as_str());
+        }
+    }
+
+    /// 流式压缩到`AsyncWrite`, 压缩在`spawn_blocking`中进行, 边压缩边写出
+    ///
+    /// 必须在 tokio runtime 中调用
+    #[cfg(feature = "zip_async")]
+    pub async fn zip_to_async_writer<W>(self, writer: W) -> Result<W>
+    where
+        W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || {
+            let bridge = tokio_util::io::SyncIoBridge::new(writer);
+            let mut bridge = self.zip_to_writer(bridge)?;
+            bridge.shutdown()?;
+            Ok(bridge.into_inner())
+        })
+        .await?
+    }
+
+    /// 往已有zip文件中添加文件, 追加模式不支持文件注释, 文件名只能用 UTF-8
+    #[allow(unused)]
+    pub fn zip_append_file(self, exists_zip_file_path: impl AsRef<std::path::Path>) -> Result<()> {
+        let zip_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(exists_zip_file_path.as_ref())?;
+        let zip_writer = ZipWriter::new_append(zip_file)?;
+        self.push_zip_file(zip_writer)?;
+        Ok(())
+    }
+
+    /// 往已有zip文件中添加文件, 追加模式不支持文件注释, 文件名只能用 UTF-8
+    pub fn zip_append_file_bytes(self, exists_zip_file_content: &mut Vec<u8>) -> Result<()> {
+        let mut zip_file = std::io::Cursor::new(exists_zip_file_content);
+        let zip_writer = ZipWriter::new_append(&mut zip_file)?;
+        self.push_zip_file(zip_writer)?;
+        Ok(())
+    }
+
+    fn push_zip_file<W>(self, mut zip_writer: ZipWriter<W>) -> Result<()>
+    where
+        W: Read + Write + Seek,
+    {
+        if !self.filename_encoding.is_utf8() {
+            return Err(anyhow!(
+                "追加模式不支持{:?}编码的文件名",
+                self.filename_encoding
+            ));
+        }
+        if let Some(comment) = self.comment {
+            zip_writer.set_comment(comment);
+        }
+        for item in self.inner {
+            item.write_to(&mut zip_writer)?;
+        }
+        zip_writer.finish()?;
+        Ok(())
+    }
+}
+
+impl InnerZipFileInfo {
+    /// 用`ZipStreamWriter`写入
+    fn write_to_stream<W: Write>(self, zip_writer: &mut ZipStreamWriter<W>) -> Result<()> {
+        let name = self.file_name.clone();
+        self.write_to_stream_as(zip_writer, &name)
+    }
+
+    /// 用`ZipStreamWriter`写入, 压缩包中的文件名使用`name`
+    fn write_to_stream_as<W: Write>(
+        mut self,
+        zip_writer: &mut ZipStreamWriter<W>,
+        name: &str,
+    ) -> Result<()> {
+        zip_writer.start_file(name, self.stream_file_options()?)?;
+        if self.file_content.is_some() {
+            zip_writer.write_all(self.file_content.take().as_deref().unwrap())?;
+        } else {
+            let mut f = std::fs::File::open(self.file_name)?;
+            std::io::copy(&mut f, zip_writer)?;
+        }
+        Ok(())
+    }
+
+    /// 单独压缩成可以原样拷贝到其他压缩包里的`RawEntry`
+    fn compress(self, encoding: FilenameEncoding) -> Result<Vec<RawEntry>> {
+        let mut zip_writer = ZipStreamWriter::new(Vec::new());
+        zip_writer.set_filename_encoding(encoding);
+        self.write_to_stream(&mut zip_writer)?;
+        zip_writer.into_raw_entries()
+    }
+
+    /// 用`ZipWriter`写入, `ZipWriter`不支持文件注释
+    fn write_to<W>(mut self, zip_writer: &mut ZipWriter<W>) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        if self.comment.is_some() {
+            return Err(anyhow!("追加压缩包时不支持文件注释: {}", self.file_name));
+        }
+        let (last_modified, unix_permissions) = self.metadata()?;
+        let mut file_options = zip::write::SimpleFileOptions::default();
+        if let Some(last_modified) = last_modified {
+            file_options = file_options.last_modified_time(last_modified);
+        }
+        if let Some(mode) = unix_permissions {
+            file_options = file_options.unix_permissions(mode);
+        }
+        zip_writer.start_file(self.file_name.as_str(), file_options)?;
+        if self.file_content.is_some() {
+            zip_writer.write_all(self.file_content.take().as_deref().unwrap())?;
+        } else {
+            let mut f = std::fs::File::open(self.file_name)?;
+            std::io::copy(&mut f, zip_writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "zip_async"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_zip_to_async_writer() {
+        let info = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("a.txt".to_string(), Some(b"aaa".to_vec())),
+            InnerZipFileInfo::new("b.txt".to_string(), Some(b"bbb".to_vec())),
+        ]);
+        // 一端写, 一端读, 模拟边压缩边发送
+        let (writer, mut reader) = tokio::io::duplex(64);
+        let handle = tokio::spawn(info.zip_to_async_writer(writer));
+        let mut bytes = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut bytes)
+            .await
+            .unwrap();
+        handle.await.unwrap().unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        let mut buf = String::new();
+        archive
+            .by_name("b.txt")
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "bbb");
+    }
+}
@@ -0,0 +1,440 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use zip::ZipArchive;
+
+use crate::archive::{ArchiveEntry, ArchiveExtractor};
+
+use super::limits::{unwrap_limit_error, LimitedReader, ZipLimits};
+use super::{datetime, FilenameEncoding, InnerZipFileInfo, ZipFileInfo};
+
+/// 读到内存时最多预分配的大小, 声明的大小不可信
+const MAX_PREALLOCATE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// zip 读取器, 读取和解压时会带上修改时间, Unix 权限和注释
+///
+/// 所有读取和解压都受`ZipLimits`限制, 默认使用`ZipLimits::default()`.
+/// 同一个`ZipReader`和它打开的嵌套压缩包共用`max_total_size`, 多次调用累计计算
+pub struct ZipReader<R> {
+    pub(super) archive: ZipArchive<R>,
+    pub(super) encoding: FilenameEncoding,
+    pub(super) limits: ZipLimits,
+    /// 已经解压出的总字节数, 和嵌套的压缩包共用
+    pub(super) total_read: Arc<AtomicU64>,
+    /// 嵌套层数, 最外层为 0
+    depth: usize,
+}
+
+impl ZipReader<File> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<'a> ZipReader<Cursor<&'a [u8]>> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        Self::new(Cursor::new(bytes))
+    }
+}
+
+impl<R: Read + Seek> ZipReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        Ok(Self {
+            archive: ZipArchive::new(reader)?,
+            encoding: FilenameEncoding::default(),
+            limits: ZipLimits::default(),
+            total_read: Arc::default(),
+            depth: 0,
+        })
+    }
+
+    /// 设置资源限制, 处理不可信的上传文件时应该按业务设置
+    pub fn with_limits(mut self, limits: ZipLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &ZipLimits {
+        &self.limits
+    }
+
+    /// 指定文件名编码, 默认自动识别 UTF-8 和 GBK, 日文等其他编码需要手动指定
+    pub fn with_encoding(mut self, encoding: FilenameEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// 压缩包注释, 和文件名使用同样的编码. 注释没有 UTF-8 标志, 按指定的编码解码
+    pub fn comment(&self) -> String {
+        self.encoding.decode(self.archive.comment(), false)
+    }
+
+    /// 压缩包中的条目数量, 包括目录
+    pub fn len(&self) -> usize {
+        self.archive.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.archive.is_empty()
+    }
+
+    /// 所有条目的文件名, 按设置的编码解码
+    pub fn file_names(&mut self) -> Result<Vec<String>> {
+        let mut names = Vec::with_capacity(self.archive.len());
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index_raw(i)?;
+            names.push(self.encoding.file_name(&file));
+        }
+        Ok(names)
+    }
+
+    /// 检查条目数量和中央目录里声明的大小, 实际解压时还会再按解压出的大小检查
+    pub(crate) fn check_limits(&mut self) -> Result<()> {
+        self.limits.check_entries(self.archive.len())?;
+        let mut total_size = 0u64;
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index_raw(i)?;
+            total_size = total_size.saturating_add(file.size());
+            self.limits.check_entry(
+                &self.encoding.file_name(&file),
+                file.size(),
+                file.compressed_size(),
+            )?;
+        }
+        self.limits.check_total_size(total_size)?;
+        Ok(())
+    }
+
+    /// 把所有文件读到内存中, 目录会被跳过
+    pub fn read_all(&mut self) -> Result<ZipFileInfo> {
+        self.check_limits()?;
+        let mut inner = Vec::with_capacity(self.archive.len());
+        for i in 0..self.archive.len() {
+            let mut file = self.archive.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+            let name = self.encoding.file_name(&file);
+            let compressed_size = file.compressed_size();
+            let mut content = Vec::with_capacity(file.size().min(MAX_PREALLOCATE_SIZE) as usize);
+            LimitedReader::new(
+                &mut file,
+                &self.limits,
+                &name,
+                compressed_size,
+                &self.total_read,
+            )
+            .read_to_end(&mut content)
+            .map_err(unwrap_limit_error)?;
+            inner.push(InnerZipFileInfo {
+                file_name: name,
+                file_content: Some(content),
+                last_modified: file.last_modified(),
+                unix_permissions: file.unix_mode().map(|mode| mode & 0o777),
+                comment: non_empty(file.comment()),
+            });
+        }
+        let mut info = ZipFileInfo::new(inner).with_filename_encoding(self.encoding);
+        info.comment = non_empty(&self.comment());
+        Ok(info)
+    }
+
+    /// 解压到目录, 会恢复文件的修改时间和 Unix 权限, 权限只保留`0o777`部分, 不还原 setuid 等特殊位.
+    /// 解压途中超出限制时会返回错误, 已经解压出的文件不会删除
+    pub fn extract_to(&mut self, directory: impl AsRef<Path>) -> Result<()> {
+        self.check_limits()?;
+        let directory = directory.as_ref();
+        // 目录可能没有写权限, 所以目录的权限等所有文件写完再设置
+        let mut dir_modes = Vec::new();
+        for i in 0..self.archive.len() {
+            let mut file = self.archive.by_index(i)?;
+            let name = self.encoding.file_name(&file);
+            let path = directory.join(enclosed_name(&name)?);
+            if file.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                if let Some(mode) = file.unix_mode() {
+                    dir_modes.push((path, mode));
+                }
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut out = File::create(&path)?;
+            let compressed_size = file.compressed_size();
+            let mut reader = LimitedReader::new(
+                &mut file,
+                &self.limits,
+                &name,
+                compressed_size,
+                &self.total_read,
+            );
+            std::io::copy(&mut reader, &mut out).map_err(unwrap_limit_error)?;
+            if let Some(last_modified) = file.last_modified() {
+                out.set_modified(datetime::to_system_time(last_modified))?;
+            }
+            if let Some(mode) = file.unix_mode() {
+                set_mode(&path, mode)?;
+            }
+        }
+        // 子目录先于父目录设置
+        dir_modes.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, mode) in &dir_modes {
+            set_mode(path, *mode)?;
+        }
+        Ok(())
+    }
+
+    /// 把一个文件读到内存中, 受`ZipLimits`中单个文件的限制
+    pub fn read_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        let index = self
+            .find_index(name)?
+            .ok_or_else(|| anyhow!("压缩包中没有文件: {name}"))?;
+        let mut file = self.archive.by_index(index)?;
+        self.limits
+            .check_entry(name, file.size(), file.compressed_size())?;
+        let compressed_size = file.compressed_size();
+        let mut content = Vec::with_capacity(file.size().min(MAX_PREALLOCATE_SIZE) as usize);
+        LimitedReader::new(
+            &mut file,
+            &self.limits,
+            name,
+            compressed_size,
+            &self.total_read,
+        )
+        .read_to_end(&mut content)
+        .map_err(unwrap_limit_error)?;
+        Ok(content)
+    }
+
+    /// 打开压缩包里的压缩包, 会整个读到内存中, 同样受`ZipLimits`限制,
+    /// 嵌套层数超过`ZipLimits::max_nesting_depth`时返回错误
+    pub fn open_nested(&mut self, name: &str) -> Result<ZipReader<Cursor<Vec<u8>>>> {
+        self.limits.check_nesting_depth(self.depth + 1)?;
+        let content = self.read_file(name)?;
+        let mut nested = ZipReader::new(Cursor::new(content))?
+            .with_encoding(self.encoding)
+            .with_limits(self.limits);
+        nested.total_read = self.total_read.clone();
+        nested.depth = self.depth + 1;
+        Ok(nested)
+    }
+
+    fn find_index(&mut self, name: &str) -> Result<Option<usize>> {
+        for i in 0..self.archive.len() {
+            let file = self.archive.by_index_raw(i)?;
+            if self.encoding.file_name(&file) == name {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// 解码后的相对路径, 绝对路径和包含`..`的路径视为不安全
+fn enclosed_name(name: &str) -> Result<PathBuf> {
+    if name.contains('\0') {
+        return Err(anyhow!("压缩包中有不安全的路径: {name}"));
+    }
+    let mut path = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("压缩包中有不安全的路径: {name}")),
+        }
+    }
+    Ok(path)
+}
+
+impl<R: Read + Seek> ArchiveExtractor for ZipReader<R> {
+    fn read_files(&mut self) -> Result<Vec<ArchiveEntry>> {
+        Ok(self
+            .read_all()?
+            .inner
+            .into_iter()
+            .map(|item| ArchiveEntry {
+                name: item.file_name,
+                content: item.file_content.unwrap_or_default(),
+                last_modified: item.last_modified.map(datetime::to_system_time),
+                unix_permissions: item.unix_permissions,
+            })
+            .collect())
+    }
+
+    fn extract_to(&mut self, directory: &Path) -> Result<()> {
+        ZipReader::extract_to(self, directory)
+    }
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::DateTime;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let last_modified = DateTime::from_date_and_time(2024, 2, 29, 13, 14, 20).unwrap();
+        let bytes = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("run.sh".to_string(), Some(b"#!/bin/sh\n".to_vec()))
+                .with_last_modified(last_modified)
+                .with_unix_permissions(0o755)
+                .with_comment("启动脚本"),
+            InnerZipFileInfo::new("a.txt".to_string(), Some(b"a".to_vec())),
+        ])
+        .with_comment("压缩包注释")
+        .zip_file_bytes()
+        .unwrap();
+
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        let info = reader.read_all().unwrap();
+        assert_eq!(info.comment.as_deref(), Some("压缩包注释"));
+        let run = &info.inner[0];
+        assert_eq!(run.last_modified, Some(last_modified));
+        assert_eq!(run.unix_permissions, Some(0o755));
+        assert_eq!(run.comment.as_deref(), Some("启动脚本"));
+        assert_eq!(info.inner[1].unix_permissions, Some(0o644));
+        assert_eq!(info.inner[1].comment, None);
+
+        let dir = std::env::temp_dir().join(format!("tool-zip-read-{}", std::process::id()));
+        reader.extract_to(&dir).unwrap();
+        let metadata = std::fs::metadata(dir.join("run.sh")).unwrap();
+        assert_eq!(
+            datetime::from_system_time(metadata.modified().unwrap()),
+            Some(last_modified)
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_directory() {
+        use crate::zip::{StreamFileOptions, ZipStreamWriter};
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        writer
+            .add_directory("ro", StreamFileOptions::default().unix_permissions(0o555))
+            .unwrap();
+        writer
+            .start_file("ro/a.txt", StreamFileOptions::default())
+            .unwrap();
+        writer.write_all(b"a").unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("tool-zip-read-ro-{}", std::process::id()));
+        ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .extract_to(&dir)
+            .unwrap();
+        let mode = std::fs::metadata(dir.join("ro"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o555);
+        assert_eq!(std::fs::read(dir.join("ro/a.txt")).unwrap(), b"a");
+        std::fs::set_permissions(dir.join("ro"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_strip_special_bits() {
+        use crate::zip::{StreamFileOptions, ZipStreamWriter};
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        writer
+            .start_file("su", StreamFileOptions::default().unix_permissions(0o4755))
+            .unwrap();
+        writer.write_all(b"#!/bin/sh\n").unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            reader.read_all().unwrap().inner[0].unix_permissions,
+            Some(0o755)
+        );
+        let dir = std::env::temp_dir().join(format!("tool-zip-read-suid-{}", std::process::id()));
+        ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .extract_to(&dir)
+            .unwrap();
+        let mode = std::fs::metadata(dir.join("su"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_gbk_file_name() {
+        let bytes = ZipFileInfo::new(vec![InnerZipFileInfo::new(
+            "报关单/申报.xml".to_string(),
+            Some(b"<xml/>".to_vec()),
+        )])
+        .with_comment("报关单")
+        .with_filename_encoding(FilenameEncoding::Gbk)
+        .zip_file_bytes()
+        .unwrap();
+
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        assert_eq!(reader.file_names().unwrap(), vec!["报关单/申报.xml"]);
+        assert_eq!(reader.comment(), "报关单");
+        let info = reader.read_all().unwrap();
+        assert_eq!(info.inner[0].file_name, "报关单/申报.xml");
+
+        let mut reader = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .with_encoding(FilenameEncoding::Utf8);
+        assert_ne!(reader.file_names().unwrap(), vec!["报关单/申报.xml"]);
+    }
+
+    #[test]
+    fn test_utf8_flag_wins() {
+        let bytes = ZipFileInfo::new(vec![InnerZipFileInfo::new(
+            "报关单/申报.xml".to_string(),
+            Some(b"<xml/>".to_vec()),
+        )])
+        .zip_file_bytes()
+        .unwrap();
+
+        let mut reader = ZipReader::from_bytes(&bytes)
+            .unwrap()
+            .with_encoding(FilenameEncoding::Gbk);
+        assert_eq!(reader.file_names().unwrap(), vec!["报关单/申报.xml"]);
+        assert_eq!(reader.read_file("报关单/申报.xml").unwrap(), b"<xml/>");
+    }
+}
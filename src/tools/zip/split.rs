@@ -0,0 +1,375 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use super::stream::ZipStreamWriter;
+use super::ZipFileInfo;
+
+/// 分卷的最小大小, 和 Info-ZIP 一致
+pub const MIN_VOLUME_SIZE: u64 = 64 * 1024;
+/// 分卷压缩最后只有一卷时, 第一卷开头的标记改成这个, 表示其实不需要分卷
+const SINGLE_VOLUME_SIGNATURE: [u8; 4] = *b"PK00";
+
+impl ZipFileInfo {
+    /// 按大小拆成多个独立的压缩包, 每个都不超过`max_part_size`字节, 可以单独解压.
+    /// 单个文件不会被拆开, 压缩后一个包放不下的文件会返回错误, 这种文件请用`zip_split_volumes`
+    ///
+    /// 每个文件先单独压缩, 再原样拷贝到所在的包里, 不会重复压缩
+    pub fn zip_split_parts(self, max_part_size: u64) -> Result<Vec<Vec<u8>>> {
+        let ZipFileInfo {
+            inner,
+            comment,
+            filename_encoding,
+        } = self;
+        let settings = ZipFileInfo {
+            inner: Vec::new(),
+            comment,
+            filename_encoding,
+        };
+        let new_part = || {
+            let mut zip_writer = ZipStreamWriter::new(Vec::new());
+            settings.configure(&mut zip_writer);
+            zip_writer
+        };
+
+        let mut parts = Vec::new();
+        let mut part = new_part();
+        let mut part_entries = 0;
+        for item in inner {
+            let name = item.file_name.clone();
+            for raw in item.compress(settings.filename_encoding)? {
+                if part_entries > 0 && part.finished_len_with(&raw)? > max_part_size {
+                    parts.push(std::mem::replace(&mut part, new_part()).finish()?);
+                    part_entries = 0;
+                }
+                if part.finished_len_with(&raw)? > max_part_size {
+                    return Err(anyhow!(
+                        "文件 {name} 压缩后放不进 {max_part_size} 字节的压缩包"
+                    ));
+                }
+                part.write_raw_entry(raw)?;
+                part_entries += 1;
+            }
+        }
+        if part_entries > 0 || parts.is_empty() {
+            parts.push(part.finish()?);
+        }
+        Ok(parts)
+    }
+
+    /// 拆成多个独立的压缩包写到磁盘, `export.zip`会拆成`export.part1.zip`,
+    /// `export.part2.zip`..., 返回所有压缩包的路径
+    pub fn zip_split_part_files(
+        self,
+        zip_file_path: impl AsRef<Path>,
+        max_part_size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        let zip_file_path = zip_file_path.as_ref();
+        self.zip_split_parts(max_part_size)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let path = zip_file_path.with_extension(format!("part{}.zip", i + 1));
+                std::fs::write(&path, part)?;
+                Ok(path)
+            })
+            .collect()
+    }
+
+    /// 标准的分卷压缩(`.z01`, `.z02`, ..., `.zip`), 每卷不超过`volume_size`字节,
+    /// 大文件会跨卷存放. 返回每一卷的内容, 最后一卷是`.zip`
+    pub fn zip_split_volumes(self, volume_size: u64) -> Result<Vec<Vec<u8>>> {
+        let Volumes::Memory(volumes) =
+            &mut self.zip_to_volumes(Volumes::Memory(vec![Vec::new()]), volume_size)?
+        else {
+            unreachable!()
+        };
+        let mut volumes = std::mem::take(volumes);
+        if volumes.len() == 1 {
+            volumes[0][..4].copy_from_slice(&SINGLE_VOLUME_SIGNATURE);
+        }
+        Ok(volumes)
+    }
+
+    /// 分卷压缩到磁盘, `export.zip`会生成`export.z01`, `export.z02`, ..., `export.zip`,
+    /// 边压缩边写, 内存占用和文件大小无关. 返回所有分卷的路径.
+    /// 失败时会删除已经写出的分卷
+    pub fn zip_split_volume_files(
+        self,
+        zip_file_path: impl AsRef<Path>,
+        volume_size: u64,
+    ) -> Result<Vec<PathBuf>> {
+        let zip_file_path = zip_file_path.as_ref();
+        let mut volumes = self.zip_to_volumes(Volumes::files(zip_file_path)?, volume_size)?;
+        let Volumes::Files { paths, current, .. } = &mut volumes else {
+            unreachable!()
+        };
+        let flushed = current.flush();
+        // 接管分卷的清理, 关闭最后一卷后再改名
+        let mut paths = std::mem::take(paths);
+        drop(volumes);
+        let result = flushed
+            .map_err(anyhow::Error::from)
+            .and_then(|_| finish_volume_files(&mut paths, zip_file_path));
+        if result.is_err() {
+            for path in &paths {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        result.map(|_| paths)
+    }
+
+    fn zip_to_volumes(self, volumes: Volumes, volume_size: u64) -> Result<Volumes> {
+        if volume_size < MIN_VOLUME_SIZE {
+            return Err(anyhow!("分卷大小不能小于 {MIN_VOLUME_SIZE} 字节"));
+        }
+        let mut zip_writer = ZipStreamWriter::new_split(volumes, volume_size, Volumes::next)?;
+        self.configure(&mut zip_writer);
+        for item in self.inner {
+            item.write_to_stream(&mut zip_writer)?;
+        }
+        zip_writer.finish()
+    }
+}
+
+/// 把最后一卷改名成`zip_file_path`, `paths`中的路径也跟着改
+fn finish_volume_files(paths: &mut [PathBuf], zip_file_path: &Path) -> Result<()> {
+    let last = paths.last_mut().unwrap();
+    std::fs::rename(&*last, zip_file_path)?;
+    *last = zip_file_path.to_path_buf();
+    if paths.len() == 1 {
+        use std::io::{Seek, SeekFrom};
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(zip_file_path)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&SINGLE_VOLUME_SIGNATURE)?;
+    }
+    Ok(())
+}
+
+/// 分卷写到哪里. 写到磁盘时, 没有被`zip_split_volume_files`接管的分卷会在 drop 时删除
+enum Volumes {
+    Memory(Vec<Vec<u8>>),
+    Files {
+        zip_file_path: PathBuf,
+        paths: Vec<PathBuf>,
+        current: BufWriter<File>,
+    },
+}
+
+impl Volumes {
+    fn files(zip_file_path: &Path) -> io::Result<Self> {
+        let path = volume_path(zip_file_path, 1);
+        Ok(Volumes::Files {
+            zip_file_path: zip_file_path.to_path_buf(),
+            current: BufWriter::new(File::create(&path)?),
+            paths: vec![path],
+        })
+    }
+
+    fn next(&mut self) -> io::Result<()> {
+        match self {
+            Volumes::Memory(volumes) => volumes.push(Vec::new()),
+            Volumes::Files {
+                zip_file_path,
+                paths,
+                current,
+            } => {
+                current.flush()?;
+                let path = volume_path(zip_file_path, paths.len() + 1);
+                *current = BufWriter::new(File::create(&path)?);
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Volumes::Memory(volumes) => volumes.last_mut().unwrap().write(buf),
+            Volumes::Files { current, .. } => current.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Volumes::Memory(_) => Ok(()),
+            Volumes::Files { current, .. } => current.flush(),
+        }
+    }
+}
+
+impl Drop for Volumes {
+    fn drop(&mut self) {
+        if let Volumes::Files { paths, .. } = self {
+            for path in paths.iter() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// 第`n`卷的路径, 从 1 开始, 如`export.z01`
+fn volume_path(zip_file_path: &Path, n: usize) -> PathBuf {
+    zip_file_path.with_extension(format!("z{n:02}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::zip::{InnerZipFileInfo, ZipReader};
+
+    /// 压缩不了的数据
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let files = (0..10)
+            .map(|i| (format!("{i}.bin"), noise(30 * 1024, i)))
+            .collect::<Vec<_>>();
+        let info = ZipFileInfo::new(
+            files
+                .iter()
+                .map(|(name, content)| InnerZipFileInfo::new(name.clone(), Some(content.clone())))
+                .collect(),
+        )
+        .with_comment("export");
+        let limit = 100 * 1024;
+        let parts = info.zip_split_parts(limit).unwrap();
+        assert_eq!(parts.len(), 4);
+
+        let mut read = Vec::new();
+        for part in &parts {
+            assert!(part.len() as u64 <= limit);
+            let mut reader = ZipReader::from_bytes(part).unwrap();
+            assert_eq!(reader.comment(), "export");
+            for item in reader.read_all().unwrap().inner {
+                read.push((item.file_name, item.file_content.unwrap()));
+            }
+        }
+        assert_eq!(read, files);
+
+        let err = ZipFileInfo::from(InnerZipFileInfo::new(
+            "big.bin".to_string(),
+            Some(noise(200 * 1024, 0)),
+        ))
+        .zip_split_parts(limit)
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("big.bin"));
+    }
+
+    #[test]
+    fn test_split_volumes() {
+        let files = vec![
+            ("small.txt".to_string(), b"small".to_vec()),
+            ("big.bin".to_string(), noise(300 * 1024, 1)),
+            ("last.txt".to_string(), b"last".repeat(1000)),
+        ];
+        let info = ZipFileInfo::new(
+            files
+                .iter()
+                .map(|(name, content)| InnerZipFileInfo::new(name.clone(), Some(content.clone())))
+                .collect(),
+        );
+        let volumes = info.zip_split_volumes(MIN_VOLUME_SIZE).unwrap();
+        assert_eq!(volumes.len(), 5);
+        assert_eq!(u32_at(&volumes[0], 0), 0x08074b50);
+        assert!(volumes.iter().all(|v| v.len() as u64 <= MIN_VOLUME_SIZE));
+
+        // 最后一卷里的结束记录
+        let last = volumes.last().unwrap();
+        let eocd = &last[last.len() - 22..];
+        assert_eq!(u32_at(eocd, 0), 0x06054b50);
+        assert_eq!(u16_at(eocd, 4) as usize, volumes.len() - 1);
+        assert_eq!(u16_at(eocd, 10), 3);
+        let (cd_disk, cd_offset) = (u16_at(eocd, 6) as usize, u32_at(eocd, 16) as usize);
+
+        // 把所有卷连起来, 按 (卷号, 偏移) 找到每个文件并解压
+        let starts = volumes
+            .iter()
+            .scan(0, |start, v| {
+                let s = *start;
+                *start += v.len();
+                Some(s)
+            })
+            .collect::<Vec<_>>();
+        let joined = volumes.concat();
+        let mut pos = starts[cd_disk] + cd_offset;
+        for (name, content) in &files {
+            let header = &joined[pos..];
+            assert_eq!(u32_at(header, 0), 0x02014b50);
+            let compressed_size = u32_at(header, 20) as usize;
+            let name_len = u16_at(header, 28) as usize;
+            let disk = u16_at(header, 34) as usize;
+            let offset = u32_at(header, 42) as usize;
+            assert_eq!(&header[46..46 + name_len], name.as_bytes());
+            pos += 46 + name_len;
+
+            let local = starts[disk] + offset;
+            assert_eq!(u32_at(&joined, local), 0x04034b50);
+            let data_start = local + 30 + name_len;
+            let mut decoded = Vec::new();
+            flate2::read::DeflateDecoder::new(&joined[data_start..data_start + compressed_size])
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(&decoded, content);
+        }
+    }
+
+    #[test]
+    fn test_single_volume_is_plain_zip() {
+        let path = std::env::temp_dir().join(format!("tool-zip-volume-{}.zip", std::process::id()));
+        let paths = ZipFileInfo::from(InnerZipFileInfo::new(
+            "a.txt".to_string(),
+            Some(b"a".to_vec()),
+        ))
+        .zip_split_volume_files(&path, MIN_VOLUME_SIZE)
+        .unwrap();
+        assert_eq!(paths, vec![path.clone()]);
+        let files = ZipReader::open(&path).unwrap().read_all().unwrap().inner;
+        assert_eq!(files[0].file_content.as_deref(), Some(b"a".as_slice()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_volume_files_cleanup() {
+        let dir =
+            std::env::temp_dir().join(format!("tool-zip-volume-cleanup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let err = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("big.bin".to_string(), Some(noise(200 * 1024, 2))),
+            InnerZipFileInfo::new("/not/exists/file.bin".to_string(), None),
+        ])
+        .zip_split_volume_files(dir.join("export.zip"), MIN_VOLUME_SIZE);
+        assert!(err.is_err());
+        // 已经写出的 .z01, .z02... 都被删掉了
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,964 @@
+use std::io::{self, Write};
+
+use anyhow::{anyhow, Result};
+use flate2::{Compress, Compression, FlushCompress, Status};
+
+use super::encoding::FilenameEncoding;
+pub use zip::{CompressionMethod, DateTime};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+/// 分卷压缩包第一卷开头的标记
+const SPLIT_SIGNATURE: u32 = 0x08074b50;
+
+const LOCAL_FILE_HEADER_LEN: u64 = 30;
+const CENTRAL_DIRECTORY_HEADER_LEN: u64 = 46;
+const END_OF_CENTRAL_DIRECTORY_LEN: u64 = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LEN: u64 = 56;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_LEN: u64 = 20;
+
+/// crc 和大小写在文件数据后面的 data descriptor 里
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// 文件名和注释使用 UTF-8 编码
+const FLAG_UTF8: u16 = 1 << 11;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// 高字节 3 表示 Unix, 这样解压时才会去读 external attributes 里的权限
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+const ZIP64_EXTRA_FIELD_TAG: u16 = 0x0001;
+/// local header 中 zip64 扩展字段的长度: tag, 长度, 原始大小, 压缩后大小
+const ZIP64_LOCAL_EXTRA_LEN: u64 = 20;
+const DEFLATE_BUFFER_SIZE: usize = 32 * 1024;
+
+const DEFAULT_FILE_PERMISSIONS: u32 = 0o644;
+const DEFAULT_DIR_PERMISSIONS: u32 = 0o755;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+/// MS-DOS 的目录属性
+const MSDOS_DIR_ATTRIBUTE: u32 = 0x10;
+
+/// 流式写入时单个文件的选项
+#[derive(Debug, Clone)]
+pub struct StreamFileOptions {
+    compression_method: CompressionMethod,
+    compression_level: Option<u32>,
+    last_modified_time: Option<DateTime>,
+    unix_permissions: Option<u32>,
+    comment: Option<String>,
+    large_file: bool,
+}
+
+impl Default for StreamFileOptions {
+    fn default() -> Self {
+        Self {
+            compression_method: CompressionMethod::Deflated,
+            compression_level: None,
+            last_modified_time: None,
+            unix_permissions: None,
+            comment: None,
+            large_file: false,
+        }
+    }
+}
+
+impl StreamFileOptions {
+    /// 压缩方式, 只支持`Stored`和`Deflated`
+    pub const fn compression_method(mut self, method: CompressionMethod) -> Self {
+        self.compression_method = method;
+        self
+    }
+
+    /// 压缩等级, 0 ~ 9, `None`使用默认等级
+    pub const fn compression_level(mut self, level: Option<u32>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// 修改时间, 不设置时使用`DateTime::default_for_write()`
+    pub const fn last_modified_time(mut self, mod_time: DateTime) -> Self {
+        self.last_modified_time = Some(mod_time);
+        self
+    }
+
+    /// Unix 权限, 如`0o755`, 不设置时文件为`0o644`, 目录为`0o755`
+    pub const fn unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_permissions = Some(mode & 0o7777);
+        self
+    }
+
+    /// 文件注释
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// 文件可能超过 4 GiB 时必须设置, local header 中会带上 zip64 扩展字段,
+    /// data descriptor 中的大小写成 8 字节. 没有设置时超过 4 GiB 会返回错误
+    pub const fn large_file(mut self, large: bool) -> Self {
+        self.large_file = large;
+        self
+    }
+}
+
+/// 写入的字节数, local header 的偏移量要用到
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+    /// 分卷写入时当前卷的状态
+    volume: Option<Volume<W>>,
+}
+
+struct Volume<W> {
+    size: u64,
+    disk: u32,
+    written: u64,
+    /// 切换到下一卷
+    next: fn(&mut W) -> io::Result<()>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// 下一个字节写入的位置: (卷号, 卷内偏移)
+    fn position(&self) -> (u32, u64) {
+        match &self.volume {
+            None => (0, self.written),
+            Some(v) if v.written >= v.size => (v.disk + 1, 0),
+            Some(v) => (v.disk, v.written),
+        }
+    }
+
+    /// 分卷时保证接下来的`len`个字节写在同一卷里, 当前卷放不下就换下一卷
+    fn reserve(&mut self, len: u64) -> io::Result<()> {
+        let Some(v) = self.volume.as_mut() else {
+            return Ok(());
+        };
+        if len > v.size {
+            return Err(io::Error::other(format!(
+                "分卷大小 {} 放不下 {len} 字节的头部信息",
+                v.size
+            )));
+        }
+        if v.written > 0 && v.written + len > v.size {
+            (v.next)(&mut self.inner)?;
+            v.disk += 1;
+            v.written = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(v) = self.volume.as_mut() else {
+            let n = self.inner.write(buf)?;
+            self.written += n as u64;
+            return Ok(n);
+        };
+        if v.written >= v.size {
+            (v.next)(&mut self.inner)?;
+            v.disk += 1;
+            v.written = 0;
+        }
+        let len = buf.len().min((v.size - v.written) as usize);
+        let n = self.inner.write(&buf[..len])?;
+        v.written += n as u64;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 写中央目录需要的信息
+#[derive(Clone)]
+struct EntryRecord {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    last_modified_time: DateTime,
+    crc32: u32,
+    compressed_size: u64,
+    size: u64,
+    /// local header 所在的卷, 不分卷时为 0
+    disk: u32,
+    /// local header 在所在卷中的偏移
+    header_offset: u64,
+    external_attributes: u32,
+    comment: Vec<u8>,
+    /// local header 中带 zip64 扩展字段, data descriptor 中的大小为 8 字节
+    large_file: bool,
+}
+
+impl EntryRecord {
+    fn is_zip64(&self) -> bool {
+        self.compressed_size >= u32::MAX as u64
+            || self.size >= u32::MAX as u64
+            || self.header_offset >= u32::MAX as u64
+    }
+
+    /// zip64 扩展字段里只放溢出的那几个值, 顺序固定
+    fn zip64_extra(&self) -> Vec<u8> {
+        let mut extra = Vec::new();
+        if self.size >= u32::MAX as u64 {
+            extra.extend_from_slice(&self.size.to_le_bytes());
+        }
+        if self.compressed_size >= u32::MAX as u64 {
+            extra.extend_from_slice(&self.compressed_size.to_le_bytes());
+        }
+        if self.header_offset >= u32::MAX as u64 {
+            extra.extend_from_slice(&self.header_offset.to_le_bytes());
+        }
+        extra
+    }
+
+    fn local_header_len(&self) -> u64 {
+        LOCAL_FILE_HEADER_LEN + self.name.len() as u64 + self.local_extra_len()
+    }
+
+    fn local_extra_len(&self) -> u64 {
+        if self.large_file {
+            ZIP64_LOCAL_EXTRA_LEN
+        } else {
+            0
+        }
+    }
+
+    fn central_directory_header_len(&self) -> u64 {
+        let extra_len = if self.is_zip64() {
+            self.zip64_extra().len() as u64 + 4
+        } else {
+            0
+        };
+        CENTRAL_DIRECTORY_HEADER_LEN
+            + self.name.len() as u64
+            + extra_len
+            + self.comment.len() as u64
+    }
+}
+
+/// 已经压缩好的文件, 包括 local header, 文件数据和 data descriptor,
+/// local header 中没有偏移量, 可以原样拷贝到其他压缩包里
+pub(super) struct RawEntry {
+    record: EntryRecord,
+    data: Vec<u8>,
+}
+
+fn is_zip64_end(
+    entry_count: u64,
+    central_directory_size: u64,
+    central_directory_start: u64,
+) -> bool {
+    entry_count >= u16::MAX as u64
+        || central_directory_size >= u32::MAX as u64
+        || central_directory_start >= u32::MAX as u64
+}
+
+/// 中央目录之后的结束记录的长度
+fn end_of_central_directory_len(
+    entry_count: u64,
+    central_directory_size: u64,
+    central_directory_start: u64,
+    comment_len: usize,
+) -> u64 {
+    let zip64_len = if is_zip64_end(entry_count, central_directory_size, central_directory_start) {
+        ZIP64_END_OF_CENTRAL_DIRECTORY_LEN + ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_LEN
+    } else {
+        0
+    };
+    zip64_len + END_OF_CENTRAL_DIRECTORY_LEN + comment_len as u64
+}
+
+/// 正在写入的文件
+struct CurrentFile {
+    record: EntryRecord,
+    hasher: crc32fast::Hasher,
+    /// `None`表示不压缩
+    compress: Option<Compress>,
+}
+
+/// 流式 zip 写入器, 只需要`Write`, 不需要`Seek`
+///
+/// 每个文件的 crc 和大小都写在文件数据后面的 data descriptor 里, 所以可以直接写到
+/// HTTP body 之类不能回退的流里, 内存占用和压缩包大小无关
+pub struct ZipStreamWriter<W: Write> {
+    inner: CountingWriter<W>,
+    entries: Vec<EntryRecord>,
+    /// 已有文件的中央目录的总长度
+    central_directory_len: u64,
+    current: Option<CurrentFile>,
+    buffer: Vec<u8>,
+    comment: String,
+    encoding: FilenameEncoding,
+}
+
+impl<W: Write> ZipStreamWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: CountingWriter {
+                inner,
+                written: 0,
+                volume: None,
+            },
+            entries: Vec::new(),
+            central_directory_len: 0,
+            current: None,
+            buffer: Vec::with_capacity(DEFLATE_BUFFER_SIZE),
+            comment: String::new(),
+            encoding: FilenameEncoding::default(),
+        }
+    }
+
+    /// 分卷写入, 每卷不超过`volume_size`, 当前卷写满时调用`next_volume`切换到下一卷.
+    /// 文件数据可以跨卷, 头部信息不会跨卷
+    pub(super) fn new_split(
+        inner: W,
+        volume_size: u64,
+        next_volume: fn(&mut W) -> io::Result<()>,
+    ) -> Result<Self> {
+        let mut writer = Self::new(inner);
+        writer.inner.volume = Some(Volume {
+            size: volume_size,
+            disk: 0,
+            written: 0,
+            next: next_volume,
+        });
+        write_u32(&mut writer.inner, SPLIT_SIGNATURE)?;
+        Ok(writer)
+    }
+
+    /// 设置压缩包注释
+    pub fn set_comment(&mut self, comment: impl Into<String>) {
+        self.comment = comment.into();
+    }
+
+    /// 设置文件名和注释的编码, 默认 UTF-8 并设置 UTF-8 标志.
+    /// 只有在需要兼容不认识 UTF-8 标志的老解压工具时, 才需要设置成 GBK 之类的编码
+    pub fn set_filename_encoding(&mut self, encoding: FilenameEncoding) {
+        self.encoding = encoding;
+    }
+
+    fn flags(&self) -> u16 {
+        if self.encoding.is_utf8() {
+            FLAG_UTF8
+        } else {
+            0
+        }
+    }
+
+    /// 开始写一个新文件, 之后通过`Write`写入文件内容, 上一个文件会自动结束
+    pub fn start_file(&mut self, name: &str, options: StreamFileOptions) -> Result<()> {
+        self.finish_file()?;
+        let compress = match options.compression_method {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflated => {
+                let level = options
+                    .compression_level
+                    .map(Compression::new)
+                    .unwrap_or_default();
+                Some(Compress::new(level, false))
+            }
+            method => return Err(anyhow!("流式写入不支持的压缩方式: {method}")),
+        };
+        let mut record = self.file_record(name, &options)?;
+        record.method = if compress.is_some() { 8 } else { 0 };
+        self.write_local_header(&mut record)?;
+        self.current = Some(CurrentFile {
+            record,
+            hasher: crc32fast::Hasher::new(),
+            compress,
+        });
+        Ok(())
+    }
+
+    /// 原样拷贝其他压缩包中已经压缩好的文件`file`(`ZipArchive::by_index_raw`读出的), 不重新压缩,
+    /// 压缩方式, crc 和大小沿用原来的, 其他信息用`options`中的
+    pub(super) fn raw_copy_file(
+        &mut self,
+        name: &str,
+        options: StreamFileOptions,
+        mut file: zip::read::ZipFile,
+    ) -> Result<()> {
+        self.finish_file()?;
+        let mut record = self.file_record(name, &options)?;
+        #[allow(deprecated)]
+        let method = file.compression().to_u16();
+        record.method = method;
+        record.large_file |= file.compressed_size().max(file.size()) >= u32::MAX as u64;
+        self.write_local_header(&mut record)?;
+        record.crc32 = file.crc32();
+        record.size = file.size();
+        record.compressed_size = io::copy(&mut file, &mut self.inner)?;
+        self.write_data_descriptor(record)
+    }
+
+    /// 普通文件的中央目录信息, 压缩方式, crc 和大小在写入数据后再填
+    fn file_record(&self, name: &str, options: &StreamFileOptions) -> Result<EntryRecord> {
+        Ok(EntryRecord {
+            name: self.encoding.encode(name)?.into_owned(),
+            flags: FLAG_DATA_DESCRIPTOR | self.flags(),
+            method: 0,
+            last_modified_time: options
+                .last_modified_time
+                .unwrap_or_else(DateTime::default_for_write),
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            disk: 0,
+            header_offset: 0,
+            external_attributes: (S_IFREG
+                | options.unix_permissions.unwrap_or(DEFAULT_FILE_PERMISSIONS))
+                << 16,
+            comment: self
+                .encoding
+                .encode(options.comment.as_deref().unwrap_or_default())?
+                .into_owned(),
+            large_file: options.large_file,
+        })
+    }
+
+    /// 添加一个目录, 压缩方式相关的选项会被忽略
+    pub fn add_directory(&mut self, name: &str, options: StreamFileOptions) -> Result<()> {
+        self.finish_file()?;
+        let mut name = name.to_string();
+        if !name.ends_with('/') {
+            name.push('/');
+        }
+        let mut record = EntryRecord {
+            name: self.encoding.encode(&name)?.into_owned(),
+            flags: self.flags(),
+            method: 0,
+            last_modified_time: options
+                .last_modified_time
+                .unwrap_or_else(DateTime::default_for_write),
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            disk: 0,
+            header_offset: 0,
+            external_attributes: ((S_IFDIR
+                | options.unix_permissions.unwrap_or(DEFAULT_DIR_PERMISSIONS))
+                << 16)
+                | MSDOS_DIR_ATTRIBUTE,
+            comment: self
+                .encoding
+                .encode(options.comment.as_deref().unwrap_or_default())?
+                .into_owned(),
+            large_file: false,
+        };
+        self.write_local_header(&mut record)?;
+        self.push_entry(record);
+        Ok(())
+    }
+
+    /// 原样写入已经压缩好的文件
+    pub(super) fn write_raw_entry(&mut self, raw: RawEntry) -> Result<()> {
+        self.finish_file()?;
+        let RawEntry { mut record, data } = raw;
+        self.inner.reserve(record.local_header_len())?;
+        (record.disk, record.header_offset) = self.inner.position();
+        self.inner.write_all(&data)?;
+        self.push_entry(record);
+        Ok(())
+    }
+
+    /// 写入`raw`后马上结束时压缩包的总大小, 用来判断是否超出大小限制
+    pub(super) fn finished_len_with(&self, raw: &RawEntry) -> Result<u64> {
+        let offset = self.inner.written;
+        let raw_header = EntryRecord {
+            header_offset: offset,
+            ..raw.record.clone()
+        };
+        let central_directory_start = offset + raw.data.len() as u64;
+        let central_directory_size =
+            self.central_directory_len + raw_header.central_directory_header_len();
+        let comment_len = self.encoding.encode(&self.comment)?.len();
+        Ok(central_directory_start
+            + central_directory_size
+            + end_of_central_directory_len(
+                self.entries.len() as u64 + 1,
+                central_directory_size,
+                central_directory_start,
+                comment_len,
+            ))
+    }
+
+    fn push_entry(&mut self, record: EntryRecord) {
+        self.central_directory_len += record.central_directory_header_len();
+        self.entries.push(record);
+    }
+
+    /// 结束所有文件并写入中央目录, 返回底层的 writer
+    pub fn finish(mut self) -> Result<W> {
+        self.finish_file()?;
+        let comment = self.encoding.encode(&self.comment)?.into_owned();
+        self.write_central_directory(&comment)?;
+        self.inner.flush()?;
+        Ok(self.inner.inner)
+    }
+
+    /// 写入 local header, 同时确定它所在的卷和偏移
+    fn write_local_header(&mut self, record: &mut EntryRecord) -> io::Result<()> {
+        // 注释写在中央目录里, 也在这里检查, 免得写到最后才出错
+        let name_len = check_len("文件名", record.name.len())?;
+        let extra_len = check_len("扩展字段", record.local_extra_len() as usize)?;
+        check_len("文件注释", record.comment.len())?;
+        self.inner.reserve(record.local_header_len())?;
+        (record.disk, record.header_offset) = self.inner.position();
+        let w = &mut self.inner;
+        write_u32(w, LOCAL_FILE_HEADER_SIGNATURE)?;
+        write_u16(
+            w,
+            if record.large_file {
+                VERSION_ZIP64
+            } else {
+                VERSION_DEFAULT
+            },
+        )?;
+        write_u16(w, record.flags)?;
+        write_u16(w, record.method)?;
+        write_u16(w, record.last_modified_time.timepart())?;
+        write_u16(w, record.last_modified_time.datepart())?;
+        // crc 和大小在 data descriptor 里, 这里都写 0
+        write_u32(w, 0)?;
+        write_u32(w, 0)?;
+        write_u32(w, 0)?;
+        write_u16(w, name_len)?;
+        write_u16(w, extra_len)?;
+        w.write_all(&record.name)?;
+        if record.large_file {
+            // 大小同样在 data descriptor 里, 这里写 0
+            write_u16(w, ZIP64_EXTRA_FIELD_TAG)?;
+            write_u16(w, (ZIP64_LOCAL_EXTRA_LEN - 4) as u16)?;
+            write_u64(w, 0)?;
+            write_u64(w, 0)?;
+        }
+        Ok(())
+    }
+
+    /// 结束当前文件, 写入 data descriptor
+    fn finish_file(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        self.write_file_data(&[], FlushCompress::Finish)?;
+        let CurrentFile {
+            mut record, hasher, ..
+        } = self.current.take().unwrap();
+        record.crc32 = hasher.finalize();
+        self.write_data_descriptor(record)
+    }
+
+    /// 在文件数据之后写入 data descriptor, 文件结束
+    fn write_data_descriptor(&mut self, record: EntryRecord) -> Result<()> {
+        // 读取时根据 local header 中有没有 zip64 扩展字段判断 data descriptor 的格式,
+        // 所以只能在开始写文件时决定
+        let is_zip64 = record.large_file;
+        if !is_zip64
+            && (record.compressed_size >= u32::MAX as u64 || record.size >= u32::MAX as u64)
+        {
+            return Err(anyhow!(
+                "文件 {} 超过 4 GiB, 需要设置 StreamFileOptions::large_file",
+                String::from_utf8_lossy(&record.name)
+            ));
+        }
+        self.inner.reserve(if is_zip64 { 24 } else { 16 })?;
+        let w = &mut self.inner;
+        write_u32(w, DATA_DESCRIPTOR_SIGNATURE)?;
+        write_u32(w, record.crc32)?;
+        if is_zip64 {
+            write_u64(w, record.compressed_size)?;
+            write_u64(w, record.size)?;
+        } else {
+            write_u32(w, record.compressed_size as u32)?;
+            write_u32(w, record.size as u32)?;
+        }
+        self.push_entry(record);
+        Ok(())
+    }
+
+    /// 写入文件数据, 需要压缩的先压缩
+    fn write_file_data(&mut self, mut data: &[u8], flush: FlushCompress) -> io::Result<()> {
+        let Some(current) = self.current.as_mut() else {
+            return Err(io::Error::other(
+                "ZipStreamWriter: 没有正在写入的文件, 请先调用 start_file",
+            ));
+        };
+        current.hasher.update(data);
+        current.record.size += data.len() as u64;
+
+        let Some(compress) = current.compress.as_mut() else {
+            self.inner.write_all(data)?;
+            current.record.compressed_size += data.len() as u64;
+            return Ok(());
+        };
+        loop {
+            self.buffer.clear();
+            let total_in = compress.total_in();
+            let status = compress
+                .compress_vec(data, &mut self.buffer, flush)
+                .map_err(io::Error::other)?;
+            data = &data[(compress.total_in() - total_in) as usize..];
+            self.inner.write_all(&self.buffer)?;
+            current.record.compressed_size += self.buffer.len() as u64;
+
+            let buffer_full = self.buffer.len() == self.buffer.capacity();
+            match flush {
+                FlushCompress::Finish if status == Status::StreamEnd => return Ok(()),
+                FlushCompress::Finish => {}
+                _ if data.is_empty() && !buffer_full => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn write_central_directory(&mut self, comment: &[u8]) -> io::Result<()> {
+        let comment_len = check_len("压缩包注释", comment.len())?;
+        let central_directory_start = self.inner.written;
+        // 中央目录开始的位置, 以及最后一卷上的记录数
+        let mut start_position = None;
+        let mut entries_on_disk = (0u32, 0u64);
+        for record in &self.entries {
+            let header = central_directory_header(record)?;
+            self.inner.reserve(header.len() as u64)?;
+            let (disk, offset) = self.inner.position();
+            start_position.get_or_insert((disk, offset));
+            if entries_on_disk.0 != disk {
+                entries_on_disk = (disk, 0);
+            }
+            entries_on_disk.1 += 1;
+            self.inner.write_all(&header)?;
+        }
+        let central_directory_size = self.inner.written - central_directory_start;
+        let entry_count = self.entries.len() as u64;
+        let is_zip64 = is_zip64_end(entry_count, central_directory_size, central_directory_start);
+
+        // 结束记录不跨卷, 这样只读最后一卷就能找到中央目录
+        self.inner.reserve(end_of_central_directory_len(
+            entry_count,
+            central_directory_size,
+            central_directory_start,
+            comment.len(),
+        ))?;
+        let (disk, end_offset) = self.inner.position();
+        let (start_disk, start_offset) = start_position.unwrap_or((disk, end_offset));
+        let entries_on_disk = if entries_on_disk.0 == disk {
+            entries_on_disk.1
+        } else {
+            0
+        };
+        let disk16 = check_disk(disk)?;
+        let start_disk16 = check_disk(start_disk)?;
+
+        let w = &mut self.inner;
+        if is_zip64 {
+            write_u32(w, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+            // 这条记录剩余部分的长度
+            write_u64(w, ZIP64_END_OF_CENTRAL_DIRECTORY_LEN - 12)?;
+            write_u16(w, VERSION_MADE_BY)?;
+            write_u16(w, VERSION_ZIP64)?;
+            write_u32(w, disk)?;
+            write_u32(w, start_disk)?;
+            write_u64(w, entries_on_disk)?;
+            write_u64(w, entry_count)?;
+            write_u64(w, central_directory_size)?;
+            write_u64(w, start_offset)?;
+
+            write_u32(w, ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE)?;
+            write_u32(w, disk)?;
+            write_u64(w, end_offset)?;
+            write_u32(w, disk + 1)?;
+        }
+
+        write_u32(w, END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        write_u16(w, disk16)?;
+        write_u16(w, start_disk16)?;
+        write_u16(w, entries_on_disk.min(u16::MAX as u64) as u16)?;
+        write_u16(w, entry_count.min(u16::MAX as u64) as u16)?;
+        write_u32(w, central_directory_size.min(u32::MAX as u64) as u32)?;
+        write_u32(w, start_offset.min(u32::MAX as u64) as u32)?;
+        write_u16(w, comment_len)?;
+        w.write_all(comment)
+    }
+}
+
+impl ZipStreamWriter<Vec<u8>> {
+    /// 结束写入, 把每个文件拆成可以原样拷贝的`RawEntry`
+    pub(super) fn into_raw_entries(mut self) -> Result<Vec<RawEntry>> {
+        self.finish_file()?;
+        let mut data = self.inner.inner;
+        let mut raw_entries = Vec::with_capacity(self.entries.len());
+        while let Some(mut record) = self.entries.pop() {
+            let entry_data = data.split_off(record.header_offset as usize);
+            record.header_offset = 0;
+            raw_entries.push(RawEntry {
+                record,
+                data: entry_data,
+            });
+        }
+        raw_entries.reverse();
+        Ok(raw_entries)
+    }
+}
+
+fn central_directory_header(record: &EntryRecord) -> io::Result<Vec<u8>> {
+    let mut w = Vec::with_capacity(record.central_directory_header_len() as usize);
+    let is_zip64 = record.is_zip64();
+    let zip64_extra = record.zip64_extra();
+    let (version_needed, extra_len) = if is_zip64 {
+        (VERSION_ZIP64, zip64_extra.len() + 4)
+    } else {
+        (VERSION_DEFAULT, 0)
+    };
+
+    write_u32(&mut w, CENTRAL_DIRECTORY_HEADER_SIGNATURE)?;
+    write_u16(&mut w, VERSION_MADE_BY)?;
+    write_u16(&mut w, version_needed)?;
+    write_u16(&mut w, record.flags)?;
+    write_u16(&mut w, record.method)?;
+    write_u16(&mut w, record.last_modified_time.timepart())?;
+    write_u16(&mut w, record.last_modified_time.datepart())?;
+    write_u32(&mut w, record.crc32)?;
+    write_u32(&mut w, record.compressed_size.min(u32::MAX as u64) as u32)?;
+    write_u32(&mut w, record.size.min(u32::MAX as u64) as u32)?;
+    write_u16(&mut w, check_len("文件名", record.name.len())?)?;
+    write_u16(&mut w, check_len("扩展字段", extra_len)?)?;
+    write_u16(&mut w, check_len("文件注释", record.comment.len())?)?;
+    write_u16(&mut w, check_disk(record.disk)?)?;
+    // 内部属性
+    write_u16(&mut w, 0)?;
+    write_u32(&mut w, record.external_attributes)?;
+    write_u32(&mut w, record.header_offset.min(u32::MAX as u64) as u32)?;
+    w.write_all(&record.name)?;
+    if is_zip64 {
+        write_u16(&mut w, ZIP64_EXTRA_FIELD_TAG)?;
+        write_u16(&mut w, check_len("扩展字段", zip64_extra.len())?)?;
+        w.write_all(&zip64_extra)?;
+    }
+    w.write_all(&record.comment)?;
+    Ok(w)
+}
+
+/// 文件名, 注释和扩展字段的长度只有 16 位, 超出时返回错误而不是截断
+fn check_len(field: &str, len: usize) -> io::Result<u16> {
+    u16::try_from(len)
+        .map_err(|_| io::Error::other(format!("{field}长度 {len} 超过 {} 字节", u16::MAX)))
+}
+
+/// 卷号只支持 16 位
+fn check_disk(disk: u32) -> io::Result<u16> {
+    u16::try_from(disk)
+        .ok()
+        .filter(|&disk| disk < u16::MAX)
+        .ok_or_else(|| io::Error::other("分卷数量过多"))
+}
+
+impl<W: Write> Write for ZipStreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_file_data(buf, FlushCompress::None)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_u16<W: Write>(w: &mut W, val: u16) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+fn write_u32<W: Write>(w: &mut W, val: u32) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+fn write_u64<W: Write>(w: &mut W, val: u64) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// 不能`Seek`的 writer
+    struct OnlyWrite(Vec<u8>);
+
+    impl Write for OnlyWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stream_writer() {
+        let content = "hello zip stream ".repeat(10000);
+        let mut writer = ZipStreamWriter::new(OnlyWrite(Vec::new()));
+        writer
+            .start_file("a.txt", StreamFileOptions::default())
+            .unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+        writer
+            .add_directory("dir", StreamFileOptions::default())
+            .unwrap();
+        writer
+            .start_file(
+                "dir/中文.txt",
+                StreamFileOptions::default().compression_method(CompressionMethod::Stored),
+            )
+            .unwrap();
+        writer.write_all(b"stored").unwrap();
+        let bytes = writer.finish().unwrap().0;
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 3);
+        let mut file = archive.by_name("a.txt").unwrap();
+        assert_eq!(file.compression(), CompressionMethod::Deflated);
+        assert!(file.compressed_size() < file.size());
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, content);
+        drop(file);
+        assert!(archive.by_name("dir/").unwrap().is_dir());
+        let mut buf = String::new();
+        archive
+            .by_name("dir/中文.txt")
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "stored");
+    }
+
+    #[test]
+    fn test_too_long() {
+        let long = "a".repeat(u16::MAX as usize + 1);
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        assert!(writer
+            .start_file(&long, StreamFileOptions::default())
+            .is_err());
+        assert!(writer
+            .start_file("a.txt", StreamFileOptions::default().comment(long.as_str()))
+            .is_err());
+
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        writer
+            .start_file("a.txt", StreamFileOptions::default())
+            .unwrap();
+        writer.set_comment(long);
+        assert!(writer.finish().is_err());
+    }
+
+    /// 只保留开头和结尾的字节, 用来检查超大文件的头部和尾部
+    struct HeadTail {
+        head: Vec<u8>,
+        tail: Vec<u8>,
+        written: u64,
+    }
+
+    impl Write for HeadTail {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let head = buf.len().min(1024 - self.head.len());
+            self.head.extend_from_slice(&buf[..head]);
+            self.tail.extend_from_slice(buf);
+            let excess = self.tail.len().saturating_sub(1024);
+            self.tail.drain(..excess);
+            self.written += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_large_file() {
+        const SIZE: u64 = u32::MAX as u64 + 1024;
+        let sink = HeadTail {
+            head: Vec::new(),
+            tail: Vec::new(),
+            written: 0,
+        };
+        let mut writer = ZipStreamWriter::new(sink);
+        let options = StreamFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(true);
+        writer.start_file("large.bin", options).unwrap();
+        let chunk = vec![0u8; 1 << 20];
+        let mut remaining = SIZE;
+        while remaining > 0 {
+            let len = remaining.min(chunk.len() as u64) as usize;
+            writer.write_all(&chunk[..len]).unwrap();
+            remaining -= len as u64;
+        }
+        let sink = writer.finish().unwrap();
+
+        // local header: 需要 zip64, 带 zip64 扩展字段, 大小都是 0
+        let head = &sink.head;
+        assert_eq!(u32_at(head, 0), LOCAL_FILE_HEADER_SIGNATURE);
+        assert_eq!(u16_at(head, 4), VERSION_ZIP64);
+        let name_len = u16_at(head, 26) as usize;
+        assert_eq!(u16_at(head, 28), ZIP64_LOCAL_EXTRA_LEN as u16);
+        let extra = &head[30 + name_len..30 + name_len + ZIP64_LOCAL_EXTRA_LEN as usize];
+        assert_eq!(u16_at(extra, 0), ZIP64_EXTRA_FIELD_TAG);
+        assert_eq!((u64_at(extra, 4), u64_at(extra, 12)), (0, 0));
+
+        // data descriptor 在文件数据之后, 大小是 8 字节
+        let data_end = LOCAL_FILE_HEADER_LEN + name_len as u64 + ZIP64_LOCAL_EXTRA_LEN + SIZE;
+        let descriptor = (data_end - (sink.written - sink.tail.len() as u64)) as usize;
+        let tail = &sink.tail;
+        assert_eq!(u32_at(tail, descriptor), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u64_at(tail, descriptor + 8), SIZE);
+        assert_eq!(u64_at(tail, descriptor + 16), SIZE);
+        assert_eq!(
+            u32_at(tail, descriptor + 24),
+            CENTRAL_DIRECTORY_HEADER_SIGNATURE
+        );
+
+        // 没有设置 large_file 时报错, 而不是写出错误的 data descriptor
+        let mut record = EntryRecord {
+            name: b"a".to_vec(),
+            flags: FLAG_DATA_DESCRIPTOR,
+            method: 0,
+            last_modified_time: DateTime::default(),
+            crc32: 0,
+            compressed_size: SIZE,
+            size: SIZE,
+            disk: 0,
+            header_offset: 0,
+            external_attributes: 0,
+            comment: Vec::new(),
+            large_file: false,
+        };
+        let mut writer = ZipStreamWriter::new(Vec::new());
+        writer.write_local_header(&mut record).unwrap();
+        writer.current = Some(CurrentFile {
+            record,
+            hasher: crc32fast::Hasher::new(),
+            compress: None,
+        });
+        assert!(writer.finish_file().is_err());
+    }
+}
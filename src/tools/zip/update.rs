@@ -0,0 +1,402 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use super::stream::{StreamFileOptions, ZipStreamWriter};
+use super::{FilenameEncoding, InnerZipFileInfo, ZipLimits, ZipReader};
+
+/// 更新已有的压缩包: 替换, 删除, 重命名和新增文件
+///
+/// 会重新生成整个压缩包, 没有改动的文件直接拷贝压缩后的数据, 不会重新压缩,
+/// 修改时间, 权限和文件注释保持不变. 被替换的文件保持原来的位置, 新增的文件放在最后.
+/// 原压缩包和更新后的条目数量受`ZipLimits`限制, 默认使用`ZipLimits::default()`.
+///
+/// 文件名按`with_encoding`指定的编码匹配, 更新后所有文件名都用 UTF-8 保存
+#[derive(Default)]
+pub struct ZipUpdate {
+    /// 替换或新增的文件
+    put: Vec<InnerZipFileInfo>,
+    remove: HashSet<String>,
+    /// 原文件名 -> 新文件名
+    rename: HashMap<String, String>,
+    limits: ZipLimits,
+    encoding: FilenameEncoding,
+}
+
+impl ZipUpdate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 替换同名文件, 不存在时新增. 同时重命名时, 替换后的文件使用新文件名.
+    /// `item`没有设置 Unix 权限时沿用被替换文件的权限
+    pub fn put(mut self, item: InnerZipFileInfo) -> Self {
+        self.put.push(item);
+        self
+    }
+
+    /// 删除文件, 文件不存在时更新会返回错误
+    pub fn remove(mut self, name: impl Into<String>) -> Self {
+        self.remove.insert(name.into());
+        self
+    }
+
+    /// 重命名文件, 文件不存在时更新会返回错误
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.rename.insert(from.into(), to.into());
+        self
+    }
+
+    /// 设置资源限制, 更新用户上传的压缩包时应该按业务设置
+    pub fn with_limits(mut self, limits: ZipLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 指定原压缩包的文件名编码, 默认自动识别 UTF-8 和 GBK, 见`ZipReader::with_encoding`
+    pub fn with_encoding(mut self, encoding: FilenameEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// 更新磁盘上的压缩包, 先写到同目录下的临时文件, 成功后再替换原文件,
+    /// 替换后的压缩包保留原文件的权限. 失败时临时文件会被删除
+    pub fn update_file(self, zip_file_path: impl AsRef<Path>) -> Result<()> {
+        let path = zip_file_path.as_ref();
+        if path.file_name().is_none() {
+            return Err(anyhow!("无效的压缩包路径: {}", path.display()));
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let source = std::fs::File::open(path)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        tmp.as_file()
+            .set_permissions(source.metadata()?.permissions())?;
+        self.update(source, tmp.as_file_mut())?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+
+    /// 更新内存中的压缩包
+    pub fn update_bytes(self, exists_zip_file_content: &mut Vec<u8>) -> Result<()> {
+        let target = self.update(
+            Cursor::new(exists_zip_file_content.as_slice()),
+            Vec::with_capacity(exists_zip_file_content.len()),
+        )?;
+        *exists_zip_file_content = target;
+        Ok(())
+    }
+
+    fn update<R, W>(self, source: R, target: W) -> Result<W>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let mut reader = ZipReader::new(source)?
+            .with_limits(self.limits)
+            .with_encoding(self.encoding);
+        reader.check_limits()?;
+        let file_names = reader.file_names()?;
+        if let Some(name) = self
+            .remove
+            .iter()
+            .chain(self.rename.keys())
+            .find(|name| !file_names.contains(name))
+        {
+            return Err(anyhow!("压缩包中没有文件: {name}"));
+        }
+
+        // 替换的文件对应`put`中的下标, 同名时用最后一个
+        let put_index = self
+            .put
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (item.file_name.clone(), i))
+            .collect::<HashMap<_, _>>();
+        self.check_names(&file_names, &put_index)?;
+
+        let mut zip_writer = ZipStreamWriter::new(target);
+        zip_writer.set_comment(reader.comment());
+        let archive = &mut reader.archive;
+        let mut put = self.put.into_iter().map(Some).collect::<Vec<_>>();
+        for (i, name) in file_names.iter().enumerate() {
+            if self.remove.contains(name) {
+                continue;
+            }
+            let new_name = self.rename.get(name).unwrap_or(name);
+            let file = archive.by_index_raw(i)?;
+            if let Some(mut item) = put_index.get(name).and_then(|&i| put[i].take()) {
+                if item.unix_permissions.is_none() {
+                    item.unix_permissions = file.unix_mode().map(|mode| mode & 0o7777);
+                }
+                drop(file);
+                item.write_to_stream_as(&mut zip_writer, new_name)?;
+                continue;
+            }
+            // 没有改动的文件原样拷贝, 文件名统一写入解码后的
+            let mut options = StreamFileOptions::default();
+            if let Some(last_modified) = file.last_modified() {
+                options = options.last_modified_time(last_modified);
+            }
+            if let Some(mode) = file.unix_mode() {
+                options = options.unix_permissions(mode);
+            }
+            if !file.comment().is_empty() {
+                options = options.comment(file.comment());
+            }
+            if file.is_dir() {
+                drop(file);
+                zip_writer.add_directory(new_name, options)?;
+            } else {
+                zip_writer.raw_copy_file(new_name, options, file)?;
+            }
+        }
+
+        // 剩下的是新增的文件, 按添加的顺序写入
+        for item in put.into_iter().flatten() {
+            item.write_to_stream(&mut zip_writer)?;
+        }
+        zip_writer.finish()
+    }
+
+    /// 写入之前先检查更新后的文件名有没有重复, 数量有没有超出限制
+    fn check_names(&self, file_names: &[String], put_index: &HashMap<String, usize>) -> Result<()> {
+        let mut names = HashSet::with_capacity(file_names.len() + self.put.len());
+        let mut replaced = HashSet::new();
+        for name in file_names {
+            if self.remove.contains(name) {
+                continue;
+            }
+            let new_name = self.rename.get(name).unwrap_or(name);
+            if !names.insert(new_name.as_str()) {
+                return Err(anyhow!("更新后有重复的文件: {new_name}"));
+            }
+            if let Some(&i) = put_index.get(name) {
+                replaced.insert(i);
+            }
+        }
+        for (i, item) in self.put.iter().enumerate() {
+            if !replaced.contains(&i) && !names.insert(item.file_name.as_str()) {
+                return Err(anyhow!("更新后有重复的文件: {}", item.file_name));
+            }
+        }
+        self.limits.check_entries(names.len())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::{ZipFileInfo, ZipLimitError};
+
+    fn build_zip() -> Vec<u8> {
+        ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("a.txt".to_string(), Some(b"a".to_vec())),
+            InnerZipFileInfo::new("manifest.json".to_string(), Some(b"{}".to_vec())),
+            InnerZipFileInfo::new("old.txt".to_string(), Some(b"old".to_vec())),
+            InnerZipFileInfo::new("keep.txt".to_string(), Some(b"keep".repeat(100))),
+        ])
+        .with_comment("comment")
+        .zip_file_bytes()
+        .unwrap()
+    }
+
+    fn update() -> ZipUpdate {
+        ZipUpdate::new()
+            .put(InnerZipFileInfo::new(
+                "manifest.json".to_string(),
+                Some(br#"{"v":2}"#.to_vec()),
+            ))
+            .put(InnerZipFileInfo::new(
+                "b.txt".to_string(),
+                Some(b"b".to_vec()),
+            ))
+            .remove("a.txt")
+            .rename("old.txt", "new.txt")
+    }
+
+    fn assert_updated(bytes: &[u8]) {
+        let mut reader = ZipReader::from_bytes(bytes).unwrap();
+        assert_eq!(reader.comment(), "comment");
+        assert!(reader.verify().unwrap().is_ok());
+        let info = reader.read_all().unwrap();
+        let files = info
+            .inner
+            .iter()
+            .map(|v| (v.file_name.as_str(), v.file_content.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ("manifest.json", br#"{"v":2}"#.as_slice()),
+                ("new.txt", b"old".as_slice()),
+                ("keep.txt", "keep".repeat(100).as_bytes()),
+                ("b.txt", b"b".as_slice()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_bytes() {
+        let mut bytes = build_zip();
+        update().update_bytes(&mut bytes).unwrap();
+        assert_updated(&bytes);
+    }
+
+    #[test]
+    fn test_update_file() {
+        let path = std::env::temp_dir().join(format!("tool-zip-update-{}.zip", std::process::id()));
+        std::fs::write(&path, build_zip()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        update().update_file(&path).unwrap();
+        assert_updated(&std::fs::read(&path).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o600);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_update_file_concurrently() {
+        let path = std::env::temp_dir().join(format!(
+            "tool-zip-update-concurrent-{}.zip",
+            std::process::id()
+        ));
+        std::fs::write(&path, build_zip()).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    ZipUpdate::new()
+                        .put(InnerZipFileInfo::new(
+                            "b.txt".to_string(),
+                            Some(b"b".to_vec()),
+                        ))
+                        .update_file(&path)
+                        .unwrap();
+                });
+            }
+        });
+        let bytes = std::fs::read(&path).unwrap();
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        assert!(reader.verify().unwrap().is_ok());
+        assert!(reader.file_names().unwrap().contains(&"b.txt".to_string()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_update_keeps_comments() {
+        let mut bytes = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("run.sh".to_string(), Some(b"echo".to_vec()))
+                .with_comment("启动脚本")
+                .with_unix_permissions(0o755),
+            InnerZipFileInfo::new("a.txt".to_string(), Some(b"a".to_vec())),
+        ])
+        .zip_file_bytes()
+        .unwrap();
+        ZipUpdate::new()
+            .put(
+                InnerZipFileInfo::new("a.txt".to_string(), Some(b"aa".to_vec()))
+                    .with_comment("替换后的注释"),
+            )
+            .rename("run.sh", "bin/run.sh")
+            .update_bytes(&mut bytes)
+            .unwrap();
+
+        let mut reader = ZipReader::from_bytes(&bytes).unwrap();
+        assert!(reader.verify().unwrap().is_ok());
+        let info = reader.read_all().unwrap();
+        assert_eq!(info.inner[0].file_name, "bin/run.sh");
+        assert_eq!(info.inner[0].comment.as_deref(), Some("启动脚本"));
+        assert_eq!(info.inner[0].unix_permissions, Some(0o755));
+        assert_eq!(info.inner[1].comment.as_deref(), Some("替换后的注释"));
+    }
+
+    #[test]
+    fn test_update_gbk() {
+        let mut bytes = ZipFileInfo::new(vec![
+            InnerZipFileInfo::new("报关单/申报.xml".to_string(), Some(b"<v1/>".to_vec()))
+                .with_unix_permissions(0o600),
+            InnerZipFileInfo::new("报关单/旧.txt".to_string(), Some(b"old".to_vec())),
+            InnerZipFileInfo::new("说明.txt".to_string(), Some(b"readme".to_vec())),
+        ])
+        .with_filename_encoding(FilenameEncoding::Gbk)
+        .zip_file_bytes()
+        .unwrap();
+        ZipUpdate::new()
+            .put(InnerZipFileInfo::new(
+                "报关单/申报.xml".to_string(),
+                Some(b"<v2/>".to_vec()),
+            ))
+            .rename("报关单/旧.txt", "报关单/新.txt")
+            .with_encoding(FilenameEncoding::Gbk)
+            .update_bytes(&mut bytes)
+            .unwrap();
+
+        let info = ZipReader::from_bytes(&bytes).unwrap().read_all().unwrap();
+        let files = info
+            .inner
+            .iter()
+            .map(|v| (v.file_name.as_str(), v.file_content.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ("报关单/申报.xml", b"<v2/>".as_slice()),
+                ("报关单/新.txt", b"old".as_slice()),
+                ("说明.txt", b"readme".as_slice()),
+            ]
+        );
+        // 替换后沿用原来的权限
+        assert_eq!(info.inner[0].unix_permissions, Some(0o600));
+    }
+
+    #[test]
+    fn test_update_errors() {
+        let mut bytes = build_zip();
+        let err = ZipUpdate::new()
+            .remove("missing.txt")
+            .update_bytes(&mut bytes)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("missing.txt"));
+        let err = ZipUpdate::new()
+            .rename("old.txt", "a.txt")
+            .update_bytes(&mut bytes)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("a.txt"));
+        // 失败时不改动原数据
+        assert_eq!(bytes, build_zip());
+
+        let limits = ZipLimits {
+            max_entries: 4,
+            ..Default::default()
+        };
+        let err = ZipUpdate::new()
+            .put(InnerZipFileInfo::new(
+                "b.txt".to_string(),
+                Some(b"b".to_vec()),
+            ))
+            .with_limits(limits)
+            .update_bytes(&mut bytes)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<ZipLimitError>().unwrap().limit_name(),
+            "max_entries"
+        );
+    }
+}